@@ -0,0 +1,46 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, ItemFn, LitStr};
+
+/// Trace every call of a function as a user event span
+///
+/// For `async fn`s a span is emitted around every poll of the returned future.
+///
+/// The span is named after the function unless a name is given: `#[instrument("name")]`
+#[proc_macro_attribute]
+pub fn instrument(args: TokenStream, item: TokenStream) -> TokenStream {
+    let function = parse_macro_input!(item as ItemFn);
+
+    let name = if args.is_empty() {
+        function.sig.ident.to_string()
+    } else {
+        parse_macro_input!(args as LitStr).value()
+    };
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+
+    let body = if sig.asyncness.is_some() {
+        quote! {
+            static __ESP_XRAY_SPAN: ::esp_xray::Span = ::esp_xray::Span::new(#name);
+            __ESP_XRAY_SPAN.instrument(async move #block).await
+        }
+    } else {
+        quote! {
+            let __esp_xray_span = ::esp_xray::span!(#name);
+            #block
+        }
+    };
+
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            #body
+        }
+    }
+    .into()
+}
@@ -0,0 +1,693 @@
+//! The format of the events sent from the target to the host
+//!
+//! Every event is an id byte followed by varint encoded fields, the last one being the time
+//! since the previous event. Events are sent in frames: `SYNC`, the length of the event, the
+//! event and a checksum over length and event.
+#![cfg_attr(not(test), no_std)]
+
+/// Start of every frame
+pub const SYNC: u8 = 0xa5;
+
+/// Longest possible encoded event
+pub const MAX_EVENT_LEN: usize = 64;
+
+/// Bytes a frame adds to the event
+pub const FRAME_OVERHEAD: usize = 3;
+
+/// Longest possible frame
+pub const MAX_FRAME_LEN: usize = MAX_EVENT_LEN + FRAME_OVERHEAD;
+
+/// Names longer than this get truncated
+pub const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// More data is needed
+    Incomplete,
+    UnknownEvent(u8),
+    /// The data isn't a valid event or frame
+    Corrupt,
+    BufferTooSmall,
+}
+
+/// Events sent by the target
+///
+/// `S` is the type of the names - `&str` when encoding or decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Event<S> {
+    TaskNew {
+        task: u32,
+        ts_delta: u32,
+    } = 1,
+    TaskExecBegin {
+        task: u32,
+        ts_delta: u32,
+    },
+    TaskExecEnd {
+        ts_delta: u32,
+    },
+    TaskReadyBegin {
+        task: u32,
+        ts_delta: u32,
+    },
+    TaskReadyEnd {
+        task: u32,
+        ts_delta: u32,
+    },
+    SystemIdle {
+        ts_delta: u32,
+    },
+    IsrEnter {
+        isr: u32,
+        ts_delta: u32,
+    },
+    IsrExit {
+        ts_delta: u32,
+    },
+    IsrExitToScheduler {
+        ts_delta: u32,
+    },
+    TaskInfo {
+        task: u32,
+        prio: u32,
+        stack_base: u32,
+        stack_size: u32,
+        name: S,
+        ts_delta: u32,
+    },
+    TaskTerminate {
+        task: u32,
+        ts_delta: u32,
+    },
+    Marker {
+        id: u32,
+        ts_delta: u32,
+    },
+    MarkerBegin {
+        id: u32,
+        ts_delta: u32,
+    },
+    MarkerEnd {
+        id: u32,
+        ts_delta: u32,
+    },
+    MarkerName {
+        id: u32,
+        name: S,
+        ts_delta: u32,
+    },
+    /// Timer ticks per second, CPU clock, RAM base and the ESP-IDF chip model - has no timestamp
+    SystemInfo {
+        sys_freq: u32,
+        cpu_freq: u32,
+        ram_base: u32,
+        chip_id: u32,
+    },
+    /// Events the target couldn't send - `ts_delta` is the time they covered
+    Overflow {
+        dropped: u32,
+        ts_delta: u32,
+    },
+}
+
+impl<S> Event<S> {
+    fn discriminant(&self) -> u8 {
+        unsafe { *(self as *const Self as *const u8) }
+    }
+
+    /// Time since the previous event
+    pub fn ts_delta(&self) -> u32 {
+        match *self {
+            Event::TaskNew { ts_delta, .. }
+            | Event::TaskExecBegin { ts_delta, .. }
+            | Event::TaskExecEnd { ts_delta }
+            | Event::TaskReadyBegin { ts_delta, .. }
+            | Event::TaskReadyEnd { ts_delta, .. }
+            | Event::SystemIdle { ts_delta }
+            | Event::IsrEnter { ts_delta, .. }
+            | Event::IsrExit { ts_delta }
+            | Event::IsrExitToScheduler { ts_delta }
+            | Event::TaskInfo { ts_delta, .. }
+            | Event::TaskTerminate { ts_delta, .. }
+            | Event::Marker { ts_delta, .. }
+            | Event::MarkerBegin { ts_delta, .. }
+            | Event::MarkerEnd { ts_delta, .. }
+            | Event::MarkerName { ts_delta, .. }
+            | Event::Overflow { ts_delta, .. } => ts_delta,
+            Event::SystemInfo { .. } => 0,
+        }
+    }
+
+    /// Convert the name of the event, if it has one
+    pub fn map_name<T>(self, f: impl FnOnce(S) -> T) -> Event<T> {
+        match self {
+            Event::TaskNew { task, ts_delta } => Event::TaskNew { task, ts_delta },
+            Event::TaskExecBegin { task, ts_delta } => Event::TaskExecBegin { task, ts_delta },
+            Event::TaskExecEnd { ts_delta } => Event::TaskExecEnd { ts_delta },
+            Event::TaskReadyBegin { task, ts_delta } => Event::TaskReadyBegin { task, ts_delta },
+            Event::TaskReadyEnd { task, ts_delta } => Event::TaskReadyEnd { task, ts_delta },
+            Event::SystemIdle { ts_delta } => Event::SystemIdle { ts_delta },
+            Event::IsrEnter { isr, ts_delta } => Event::IsrEnter { isr, ts_delta },
+            Event::IsrExit { ts_delta } => Event::IsrExit { ts_delta },
+            Event::IsrExitToScheduler { ts_delta } => Event::IsrExitToScheduler { ts_delta },
+            Event::TaskInfo {
+                task,
+                prio,
+                stack_base,
+                stack_size,
+                name,
+                ts_delta,
+            } => Event::TaskInfo {
+                task,
+                prio,
+                stack_base,
+                stack_size,
+                name: f(name),
+                ts_delta,
+            },
+            Event::TaskTerminate { task, ts_delta } => Event::TaskTerminate { task, ts_delta },
+            Event::Marker { id, ts_delta } => Event::Marker { id, ts_delta },
+            Event::MarkerBegin { id, ts_delta } => Event::MarkerBegin { id, ts_delta },
+            Event::MarkerEnd { id, ts_delta } => Event::MarkerEnd { id, ts_delta },
+            Event::MarkerName { id, name, ts_delta } => Event::MarkerName {
+                id,
+                name: f(name),
+                ts_delta,
+            },
+            Event::SystemInfo {
+                sys_freq,
+                cpu_freq,
+                ram_base,
+                chip_id,
+            } => Event::SystemInfo {
+                sys_freq,
+                cpu_freq,
+                ram_base,
+                chip_id,
+            },
+            Event::Overflow { dropped, ts_delta } => Event::Overflow { dropped, ts_delta },
+        }
+    }
+}
+
+impl<S: AsRef<str>> Event<S> {
+    /// Encode the event into `buffer` - returns the number of bytes written
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer { buffer, pos: 0 };
+        writer.u8(self.discriminant())?;
+
+        match self {
+            Event::TaskNew { task, ts_delta }
+            | Event::TaskExecBegin { task, ts_delta }
+            | Event::TaskReadyBegin { task, ts_delta }
+            | Event::TaskReadyEnd { task, ts_delta }
+            | Event::TaskTerminate { task, ts_delta } => {
+                writer.u32(*task)?;
+                writer.u32(*ts_delta)?;
+            }
+            Event::TaskExecEnd { ts_delta }
+            | Event::SystemIdle { ts_delta }
+            | Event::IsrExit { ts_delta }
+            | Event::IsrExitToScheduler { ts_delta } => {
+                writer.u32(*ts_delta)?;
+            }
+            Event::IsrEnter { isr, ts_delta } => {
+                writer.u32(*isr)?;
+                writer.u32(*ts_delta)?;
+            }
+            Event::TaskInfo {
+                task,
+                prio,
+                stack_base,
+                stack_size,
+                name,
+                ts_delta,
+            } => {
+                writer.u32(*task)?;
+                writer.u32(*prio)?;
+                writer.u32(*stack_base)?;
+                writer.u32(*stack_size)?;
+                writer.str(name.as_ref())?;
+                writer.u32(*ts_delta)?;
+            }
+            Event::Marker { id, ts_delta }
+            | Event::MarkerBegin { id, ts_delta }
+            | Event::MarkerEnd { id, ts_delta } => {
+                writer.u32(*id)?;
+                writer.u32(*ts_delta)?;
+            }
+            Event::MarkerName { id, name, ts_delta } => {
+                writer.u32(*id)?;
+                writer.str(name.as_ref())?;
+                writer.u32(*ts_delta)?;
+            }
+            Event::SystemInfo {
+                sys_freq,
+                cpu_freq,
+                ram_base,
+                chip_id,
+            } => {
+                writer.u32(*sys_freq)?;
+                writer.u32(*cpu_freq)?;
+                writer.u32(*ram_base)?;
+                writer.u32(*chip_id)?;
+            }
+            Event::Overflow { dropped, ts_delta } => {
+                writer.u32(*dropped)?;
+                writer.u32(*ts_delta)?;
+            }
+        }
+
+        Ok(writer.pos)
+    }
+
+    /// Encode the event as a frame into `buffer` - returns the number of bytes written
+    pub fn encode_frame(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let payload = buffer.get_mut(2..).ok_or(Error::BufferTooSmall)?;
+        let len = self.encode(payload)?;
+        let frame = buffer
+            .get_mut(..len + FRAME_OVERHEAD)
+            .ok_or(Error::BufferTooSmall)?;
+
+        frame[0] = SYNC;
+        frame[1] = len as u8;
+        frame[len + 2] = checksum(&frame[1..][..len + 1]);
+
+        Ok(len + FRAME_OVERHEAD)
+    }
+}
+
+impl<'a> Event<&'a str> {
+    /// Decode the event at the start of `buffer` - returns the event and its length
+    pub fn decode(buffer: &'a [u8]) -> Result<(Self, usize), Error> {
+        let mut reader = Reader { buffer, pos: 0 };
+        let id = reader.u8()?;
+
+        let event = match id {
+            1 => Event::TaskNew {
+                task: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            2 => Event::TaskExecBegin {
+                task: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            3 => Event::TaskExecEnd {
+                ts_delta: reader.u32()?,
+            },
+            4 => Event::TaskReadyBegin {
+                task: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            5 => Event::TaskReadyEnd {
+                task: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            6 => Event::SystemIdle {
+                ts_delta: reader.u32()?,
+            },
+            7 => Event::IsrEnter {
+                isr: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            8 => Event::IsrExit {
+                ts_delta: reader.u32()?,
+            },
+            9 => Event::IsrExitToScheduler {
+                ts_delta: reader.u32()?,
+            },
+            10 => Event::TaskInfo {
+                task: reader.u32()?,
+                prio: reader.u32()?,
+                stack_base: reader.u32()?,
+                stack_size: reader.u32()?,
+                name: reader.str()?,
+                ts_delta: reader.u32()?,
+            },
+            11 => Event::TaskTerminate {
+                task: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            12 => Event::Marker {
+                id: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            13 => Event::MarkerBegin {
+                id: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            14 => Event::MarkerEnd {
+                id: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            15 => Event::MarkerName {
+                id: reader.u32()?,
+                name: reader.str()?,
+                ts_delta: reader.u32()?,
+            },
+            16 => Event::SystemInfo {
+                sys_freq: reader.u32()?,
+                cpu_freq: reader.u32()?,
+                ram_base: reader.u32()?,
+                chip_id: reader.u32()?,
+            },
+            17 => Event::Overflow {
+                dropped: reader.u32()?,
+                ts_delta: reader.u32()?,
+            },
+            _ => return Err(Error::UnknownEvent(id)),
+        };
+
+        Ok((event, reader.pos))
+    }
+
+    /// Decode the frame at the start of `buffer` - returns the event and the length of the frame
+    pub fn decode_frame(buffer: &'a [u8]) -> Result<(Self, usize), Error> {
+        if *buffer.first().ok_or(Error::Incomplete)? != SYNC {
+            return Err(Error::Corrupt);
+        }
+
+        let len = *buffer.get(1).ok_or(Error::Incomplete)? as usize;
+        if len > MAX_EVENT_LEN {
+            return Err(Error::Corrupt);
+        }
+
+        let frame = buffer
+            .get(..len + FRAME_OVERHEAD)
+            .ok_or(Error::Incomplete)?;
+        if checksum(&frame[1..][..len + 1]) != frame[len + 2] {
+            return Err(Error::Corrupt);
+        }
+
+        match Event::decode(&frame[2..][..len]) {
+            Ok((event, decoded)) if decoded == len => Ok((event, len + FRAME_OVERHEAD)),
+            Ok(_) | Err(Error::Incomplete) => Err(Error::Corrupt),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Additive checksum over the length byte and the event
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Write `value` as LEB128 varint at `pos` - returns the position after it
+pub fn encode_u32(mut value: u32, buffer: &mut [u8], mut pos: usize) -> Result<usize, Error> {
+    loop {
+        let byte = buffer.get_mut(pos).ok_or(Error::BufferTooSmall)?;
+        pos += 1;
+
+        if value > 0x7f {
+            *byte = (value | 0x80) as u8;
+            value >>= 7;
+        } else {
+            *byte = value as u8;
+            break Ok(pos);
+        }
+    }
+}
+
+/// Read a LEB128 varint at `pos` - returns the value and the position after it
+pub fn decode_u32(buffer: &[u8], mut pos: usize) -> Result<(u32, usize), Error> {
+    let mut value = 0u32;
+
+    for shift in (0..32).step_by(7) {
+        let byte = *buffer.get(pos).ok_or(Error::Incomplete)?;
+        pos += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok((value, pos));
+        }
+    }
+
+    Err(Error::Corrupt)
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn u8(&mut self, value: u8) -> Result<(), Error> {
+        *self.buffer.get_mut(self.pos).ok_or(Error::BufferTooSmall)? = value;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), Error> {
+        self.pos = encode_u32(value, self.buffer, self.pos)?;
+        Ok(())
+    }
+
+    /// Names are truncated to `MAX_NAME_LEN` bytes, at a character boundary
+    fn str(&mut self, s: &str) -> Result<(), Error> {
+        let mut len = usize::min(s.len(), MAX_NAME_LEN);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.u32(len as u32)?;
+        self.buffer
+            .get_mut(self.pos..self.pos + len)
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(&s.as_bytes()[..len]);
+        self.pos += len;
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, Error> {
+        let value = *self.buffer.get(self.pos).ok_or(Error::Incomplete)?;
+        self.pos += 1;
+        Ok(value)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let (value, pos) = decode_u32(self.buffer, self.pos)?;
+        self.pos = pos;
+        Ok(value)
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        let len = self.u32()? as usize;
+        let bytes = self
+            .buffer
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or(Error::Incomplete)?;
+        self.pos += len;
+
+        core::str::from_utf8(bytes).map_err(|_| Error::Corrupt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_u32() {
+        let mut buffer = [0u8; 5];
+        assert_eq!(Ok(1), encode_u32(0x7f, &mut buffer, 0));
+        assert_eq!(0x7f, buffer[0]);
+        assert_eq!(Ok(3), encode_u32(300, &mut buffer, 1));
+        assert_eq!(&[0xac, 0x02], &buffer[1..3]);
+        assert_eq!(
+            Err(Error::BufferTooSmall),
+            encode_u32(u32::MAX, &mut buffer, 1)
+        );
+    }
+
+    #[test]
+    fn test_decode_u32() {
+        assert_eq!(Ok((300, 2)), decode_u32(&[0xac, 0x02], 0));
+        assert_eq!(Err(Error::Incomplete), decode_u32(&[0xac], 0));
+        assert_eq!(
+            Err(Error::Corrupt),
+            decode_u32(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x01], 0)
+        );
+    }
+
+    #[test]
+    fn test_encode_event() {
+        let mut buffer = [0u8; MAX_EVENT_LEN];
+        let event: Event<&str> = Event::IsrEnter {
+            isr: 17,
+            ts_delta: 300,
+        };
+        let len = event.encode(&mut buffer).unwrap();
+        assert_eq!(&[7, 17, 0xac, 0x02], &buffer[..len]);
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let event: Event<&str> = Event::SystemIdle { ts_delta: 10 };
+        let len = event.encode_frame(&mut buffer).unwrap();
+        assert_eq!(&[SYNC, 2, 6, 10, 18], &buffer[..len]);
+    }
+
+    #[test]
+    fn test_truncate_name() {
+        let mut buffer = [0u8; MAX_EVENT_LEN];
+        let name = format!("x{}", "äöü".repeat(10));
+        let len = Event::MarkerName {
+            id: 1,
+            name: name.as_str(),
+            ts_delta: 0,
+        }
+        .encode(&mut buffer)
+        .unwrap();
+
+        let (event, _) = Event::decode(&buffer[..len]).unwrap();
+        assert_eq!(
+            Event::MarkerName {
+                id: 1,
+                name: &name[..31],
+                ts_delta: 0
+            },
+            event
+        );
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        assert_eq!(Err(Error::UnknownEvent(0)), Event::decode(&[0, 1, 2]));
+        assert_eq!(Err(Error::Incomplete), Event::decode(&[1, 1]));
+    }
+
+    #[test]
+    fn test_decode_bad_frame() {
+        assert_eq!(Err(Error::Corrupt), Event::decode_frame(&[0, 2, 6, 10, 18]));
+        assert_eq!(
+            Err(Error::Corrupt),
+            Event::decode_frame(&[SYNC, 2, 6, 10, 19])
+        );
+        assert_eq!(
+            Err(Error::Corrupt),
+            Event::decode_frame(&[SYNC, 3, 6, 10, 0, 19])
+        );
+        assert_eq!(
+            Err(Error::Incomplete),
+            Event::decode_frame(&[SYNC, 2, 6, 10])
+        );
+    }
+
+    fn name() -> impl Strategy<Value = String> {
+        "\\PC{0,32}".prop_map(|s| {
+            let mut len = usize::min(s.len(), MAX_NAME_LEN);
+            while !s.is_char_boundary(len) {
+                len -= 1;
+            }
+            String::from(&s[..len])
+        })
+    }
+
+    fn event() -> impl Strategy<Value = Event<String>> {
+        let n = any::<u32>;
+        prop_oneof![
+            (n(), n()).prop_map(|(task, ts_delta)| Event::TaskNew { task, ts_delta }),
+            (n(), n()).prop_map(|(task, ts_delta)| Event::TaskExecBegin { task, ts_delta }),
+            n().prop_map(|ts_delta| Event::TaskExecEnd { ts_delta }),
+            (n(), n()).prop_map(|(task, ts_delta)| Event::TaskReadyBegin { task, ts_delta }),
+            (n(), n()).prop_map(|(task, ts_delta)| Event::TaskReadyEnd { task, ts_delta }),
+            n().prop_map(|ts_delta| Event::SystemIdle { ts_delta }),
+            (n(), n()).prop_map(|(isr, ts_delta)| Event::IsrEnter { isr, ts_delta }),
+            n().prop_map(|ts_delta| Event::IsrExit { ts_delta }),
+            n().prop_map(|ts_delta| Event::IsrExitToScheduler { ts_delta }),
+            (n(), n(), n(), n(), name(), n()).prop_map(
+                |(task, prio, stack_base, stack_size, name, ts_delta)| Event::TaskInfo {
+                    task,
+                    prio,
+                    stack_base,
+                    stack_size,
+                    name,
+                    ts_delta,
+                }
+            ),
+            (n(), n()).prop_map(|(task, ts_delta)| Event::TaskTerminate { task, ts_delta }),
+            (n(), n()).prop_map(|(id, ts_delta)| Event::Marker { id, ts_delta }),
+            (n(), n()).prop_map(|(id, ts_delta)| Event::MarkerBegin { id, ts_delta }),
+            (n(), n()).prop_map(|(id, ts_delta)| Event::MarkerEnd { id, ts_delta }),
+            (n(), name(), n()).prop_map(|(id, name, ts_delta)| Event::MarkerName {
+                id,
+                name,
+                ts_delta
+            }),
+            (n(), n(), n(), n()).prop_map(|(sys_freq, cpu_freq, ram_base, chip_id)| {
+                Event::SystemInfo {
+                    sys_freq,
+                    cpu_freq,
+                    ram_base,
+                    chip_id,
+                }
+            }),
+            (n(), n()).prop_map(|(dropped, ts_delta)| Event::Overflow { dropped, ts_delta }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_u32_roundtrip(value: u32, pos in 0usize..4) {
+            let mut buffer = [0u8; 9];
+            let end = encode_u32(value, &mut buffer, pos).unwrap();
+            prop_assert_eq!(Ok((value, end)), decode_u32(&buffer, pos));
+        }
+
+        #[test]
+        fn prop_event_roundtrip(event in event()) {
+            let mut buffer = [0u8; MAX_EVENT_LEN];
+            let len = event.encode(&mut buffer).unwrap();
+            let (decoded, decoded_len) = Event::decode(&buffer[..len]).unwrap();
+            prop_assert_eq!(len, decoded_len);
+            prop_assert_eq!(event, decoded.map_name(String::from));
+        }
+
+        #[test]
+        fn prop_event_incomplete(event in event()) {
+            let mut buffer = [0u8; MAX_EVENT_LEN];
+            let len = event.encode(&mut buffer).unwrap();
+            for short in 0..len {
+                prop_assert_eq!(Err(Error::Incomplete), Event::decode(&buffer[..short]));
+            }
+        }
+
+        #[test]
+        fn prop_frame_roundtrip(event in event()) {
+            let mut buffer = [0u8; MAX_FRAME_LEN];
+            let len = event.encode_frame(&mut buffer).unwrap();
+            let (decoded, decoded_len) = Event::decode_frame(&buffer[..len]).unwrap();
+            prop_assert_eq!(len, decoded_len);
+            prop_assert_eq!(event, decoded.map_name(String::from));
+        }
+
+        #[test]
+        fn prop_frame_detects_corrupt_byte(event in event(), index: prop::sample::Index, flip in 1u8..) {
+            let mut buffer = [0u8; MAX_FRAME_LEN];
+            let len = event.encode_frame(&mut buffer).unwrap();
+
+            // the length is covered by the frame boundaries, the checksum by the payload
+            let index = 2 + index.index(len - 2);
+            buffer[index] ^= flip;
+            prop_assert!(Event::decode_frame(&buffer[..len]).is_err());
+        }
+
+        #[test]
+        fn prop_decode_never_panics(data: Vec<u8>) {
+            let _ = Event::decode(&data);
+            let _ = Event::decode_frame(&data);
+        }
+    }
+}
@@ -1,91 +1,340 @@
 #![no_std]
 
-use core::cell::RefCell;
+use core::cell::{RefCell, RefMut};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
 
-use critical_section::Mutex;
+use critical_section::{CriticalSection, Mutex};
+use esp_xray_protocol::{Event, MAX_FRAME_LEN};
 use rtos_trace::RtosTrace;
 use rtt_target::ChannelMode::NoBlockSkip;
-use rtt_target::{rtt_init, UpChannel};
+use rtt_target::UpChannel;
 
-struct RtosTraceImpl;
+pub use rtt_target::ChannelMode;
 
-enum Event {
-    TaskNew = 1,
-    TaskExecBegin,
-    TaskExecEnd,
-    TaskReadyBegin,
-    TaskReadyEnd,
-    SystemIdle,
-}
+pub use esp_xray_macros::instrument;
+
+struct RtosTraceImpl;
 
 impl RtosTrace for RtosTraceImpl {
     fn task_new(id: u32) {
-        let mut buffer = [0u8; 16];
-        buffer[0] = Event::TaskNew as u8;
-        let pos = encode_u32(id, &mut buffer, 1);
-        let pos = encode_u32(get_ts_delta(), &mut buffer, pos);
-        post(&buffer[..pos]);
+        post(&Event::TaskNew {
+            task: id,
+            ts_delta: get_ts_delta(),
+        });
     }
 
     fn task_exec_begin(id: u32) {
-        let mut buffer = [0u8; 16];
-        buffer[0] = Event::TaskExecBegin as u8;
-        let pos = encode_u32(id, &mut buffer, 1);
-        let pos = encode_u32(get_ts_delta(), &mut buffer, pos);
-        post(&buffer[..pos]);
+        post(&Event::TaskExecBegin {
+            task: id,
+            ts_delta: get_ts_delta(),
+        });
     }
 
     fn task_exec_end() {
-        let mut buffer = [0u8; 16];
-        buffer[0] = Event::TaskExecEnd as u8;
-        let pos = encode_u32(get_ts_delta(), &mut buffer, 1);
-        post(&buffer[..pos]);
+        post(&Event::TaskExecEnd {
+            ts_delta: get_ts_delta(),
+        });
     }
 
     fn task_ready_begin(id: u32) {
-        let mut buffer = [0u8; 16];
-        buffer[0] = Event::TaskReadyBegin as u8;
-        let pos = encode_u32(id, &mut buffer, 1);
-        let pos = encode_u32(get_ts_delta(), &mut buffer, pos);
-        post(&buffer[..pos]);
+        post(&Event::TaskReadyBegin {
+            task: id,
+            ts_delta: get_ts_delta(),
+        });
     }
 
     fn task_ready_end(id: u32) {
-        let mut buffer = [0u8; 16];
-        buffer[0] = Event::TaskReadyEnd as u8;
-        let pos = encode_u32(id, &mut buffer, 1);
-        let pos = encode_u32(get_ts_delta(), &mut buffer, pos);
-        post(&buffer[..pos]);
+        post(&Event::TaskReadyEnd {
+            task: id,
+            ts_delta: get_ts_delta(),
+        });
     }
 
     fn system_idle() {
-        let mut buffer = [0u8; 16];
-        buffer[0] = Event::SystemIdle as u8;
-        let pos = encode_u32(get_ts_delta(), &mut buffer, 1);
-        post(&buffer[..pos]);
+        post(&Event::SystemIdle {
+            ts_delta: get_ts_delta(),
+        });
     }
 
-    fn task_send_info(_id: u32, _info: rtos_trace::TaskInfo) {}
+    fn task_send_info(id: u32, info: rtos_trace::TaskInfo) {
+        post(&Event::TaskInfo {
+            task: id,
+            prio: info.priority,
+            stack_base: info.stack_base as u32,
+            stack_size: info.stack_size as u32,
+            name: info.name,
+            ts_delta: get_ts_delta(),
+        });
+    }
 
-    fn task_terminate(_id: u32) {}
+    fn task_terminate(id: u32) {
+        post(&Event::TaskTerminate {
+            task: id,
+            ts_delta: get_ts_delta(),
+        });
+    }
 
-    fn isr_enter() {}
+    fn isr_enter() {
+        post(&Event::IsrEnter {
+            isr: current_interrupt(),
+            ts_delta: get_ts_delta(),
+        });
+    }
 
-    fn isr_exit() {}
+    fn isr_exit() {
+        post(&Event::IsrExit {
+            ts_delta: get_ts_delta(),
+        });
+    }
 
-    fn isr_exit_to_scheduler() {}
+    fn isr_exit_to_scheduler() {
+        post(&Event::IsrExitToScheduler {
+            ts_delta: get_ts_delta(),
+        });
+    }
 
-    fn marker(_id: u32) {}
+    fn marker(id: u32) {
+        post(&Event::Marker {
+            id,
+            ts_delta: get_ts_delta(),
+        });
+    }
 
-    fn marker_begin(_id: u32) {}
+    fn marker_begin(id: u32) {
+        post(&Event::MarkerBegin {
+            id,
+            ts_delta: get_ts_delta(),
+        });
+    }
 
-    fn marker_end(_id: u32) {}
+    fn marker_end(id: u32) {
+        post(&Event::MarkerEnd {
+            id,
+            ts_delta: get_ts_delta(),
+        });
+    }
 }
 
 rtos_trace::global_trace! {RtosTraceImpl}
 
+/// Record a single point in time, shown as a zero-length user event in SystemView
+pub fn marker(id: u32) {
+    RtosTraceImpl::marker(id);
+}
+
+/// Begin a user event span, shown in SystemView until the matching [marker_end]
+pub fn marker_begin(id: u32) {
+    RtosTraceImpl::marker_begin(id);
+}
+
+/// End a user event span started by [marker_begin]
+pub fn marker_end(id: u32) {
+    RtosTraceImpl::marker_end(id);
+}
+
+/// Give the user event `id` a name
+///
+/// SystemView has no names for user events - it shows the name as the name of the resource `id`.
+/// The Perfetto and CTF exports use it for the user event.
+pub fn marker_name(id: u32, name: &str) {
+    post(&Event::MarkerName {
+        id,
+        name,
+        ts_delta: get_ts_delta(),
+    });
+}
+
+/// Enter a named span which ends when the returned guard is dropped
+///
+/// ```ignore
+/// let _span = esp_xray::span!("sensor read");
+/// ```
+///
+/// Note that `let _ = span!(..)` drops the guard (and ends the span) right away.
+#[macro_export]
+macro_rules! span {
+    ($name:expr) => {{
+        static SPAN: $crate::Span = $crate::Span::new($name);
+        SPAN.enter()
+    }};
+}
+
+/// A named user event span
+///
+/// The user event id is derived from the name. The name is sent to the host the first time the span is entered - see
+/// [marker_name] for where it shows up.
+pub struct Span {
+    name: &'static str,
+    id: u32,
+    announced: AtomicBool,
+}
+
+impl Span {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            id: span_id(name),
+            announced: AtomicBool::new(false),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Begin the span - it ends when the guard is dropped
+    pub fn enter(&self) -> SpanGuard {
+        self.announce();
+        SpanGuard::new(self.id)
+    }
+
+    /// Wrap a future so every poll of it is traced as this span
+    pub fn instrument<F: Future>(&'static self, future: F) -> Instrumented<F> {
+        Instrumented { span: self, future }
+    }
+
+    fn announce(&self) {
+        #[cfg(target_has_atomic = "8")]
+        let first = !self.announced.swap(true, Ordering::Relaxed);
+
+        // riscv32imc and the ESP32-S2 have no atomic swap
+        #[cfg(not(target_has_atomic = "8"))]
+        let first = critical_section::with(|_| {
+            let announced = self.announced.load(Ordering::Relaxed);
+            self.announced.store(true, Ordering::Relaxed);
+            !announced
+        });
+
+        if first {
+            marker_name(self.id, self.name);
+        }
+    }
+}
+
+/// Ends a user event span when dropped
+pub struct SpanGuard {
+    id: u32,
+}
+
+impl SpanGuard {
+    pub fn new(id: u32) -> Self {
+        marker_begin(id);
+        Self { id }
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        marker_end(self.id);
+    }
+}
+
+/// A future traced by [Span::instrument]
+pub struct Instrumented<F> {
+    span: &'static Span,
+    future: F,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `future` is structurally pinned and never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+        let _guard = this.span.enter();
+        unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx)
+    }
+}
+
+/// FNV-1a hash of the span name
+const fn span_id(name: &str) -> u32 {
+    let bytes = name.as_bytes();
+    let mut hash = 0x811c9dc5u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x01000193);
+        i += 1;
+    }
+    hash
+}
+
 static CHANNEL: Mutex<RefCell<Option<UpChannel>>> = Mutex::new(RefCell::new(None));
 static LAST_TS: Mutex<RefCell<u64>> = Mutex::new(RefCell::new(0));
+static DROPPED: Mutex<RefCell<Dropped>> = Mutex::new(RefCell::new(Dropped {
+    events: 0,
+    ts_delta: 0,
+}));
+static CPU_FREQUENCY: Mutex<RefCell<u32>> = Mutex::new(RefCell::new(chip::CPU_FREQUENCY));
+static CONFIG: Mutex<RefCell<Config>> = Mutex::new(RefCell::new(Config { mode: NoBlockSkip }));
+
+#[cfg(feature = "esp32c2")]
+mod chip {
+    pub const ID: u32 = 12;
+    pub const RAM_BASE: u32 = 0x3fca_0000;
+    pub const CPU_FREQUENCY: u32 = 120_000_000;
+}
+
+#[cfg(feature = "esp32c3")]
+mod chip {
+    pub const ID: u32 = 5;
+    pub const RAM_BASE: u32 = 0x3fc8_0000;
+    pub const CPU_FREQUENCY: u32 = 160_000_000;
+}
+
+#[cfg(feature = "esp32c6")]
+mod chip {
+    pub const ID: u32 = 13;
+    pub const RAM_BASE: u32 = 0x4080_0000;
+    pub const CPU_FREQUENCY: u32 = 160_000_000;
+}
+
+#[cfg(feature = "esp32h2")]
+mod chip {
+    pub const ID: u32 = 16;
+    pub const RAM_BASE: u32 = 0x4080_0000;
+    pub const CPU_FREQUENCY: u32 = 96_000_000;
+}
+
+#[cfg(feature = "esp32s2")]
+mod chip {
+    pub const ID: u32 = 2;
+    pub const RAM_BASE: u32 = 0x3ffb_0000;
+    pub const CPU_FREQUENCY: u32 = 240_000_000;
+}
+
+#[cfg(feature = "esp32s3")]
+mod chip {
+    pub const ID: u32 = 9;
+    pub const RAM_BASE: u32 = 0x3fc8_8000;
+    pub const CPU_FREQUENCY: u32 = 240_000_000;
+}
+
+/// Report the actual clock configuration to the host
+///
+/// Until this is called the maximum CPU clock of the chip is assumed.
+pub fn set_clocks(clocks: &esp_hal::clock::Clocks) {
+    let cpu_frequency = clocks.cpu_clock.to_Hz();
+    critical_section::with(|cs| CPU_FREQUENCY.replace(cs, cpu_frequency));
+
+    post(&system_info(cpu_frequency));
+}
+
+fn system_info(cpu_frequency: u32) -> Event<&'static str> {
+    Event::SystemInfo {
+        sys_freq: tick_rate(esp_hal::time::current_time()),
+        cpu_freq: cpu_frequency,
+        ram_base: chip::RAM_BASE,
+        chip_id: chip::ID,
+    }
+}
+
+fn tick_rate<const NOM: u32, const DENOM: u32>(_: fugit::Instant<u64, NOM, DENOM>) -> u32 {
+    DENOM / NOM
+}
 
 fn get_ts_delta() -> u32 {
     critical_section::with(|cs| {
@@ -101,34 +350,155 @@ fn get_ts_delta() -> u32 {
     })
 }
 
-fn encode_u32(mut value: u32, buffer: &mut [u8], mut count: usize) -> usize {
-    while value > 0x7F {
-        buffer[count] = (value | 0x80) as u8;
-        count += 1;
-        value >>= 7;
+/// Number of the CPU interrupt currently being serviced
+#[cfg(target_arch = "riscv32")]
+fn current_interrupt() -> u32 {
+    riscv::register::mcause::read().code() as u32
+}
+
+/// Number of the CPU interrupt currently being serviced
+///
+/// Xtensa doesn't tell us which interrupt got taken - use the highest pending and enabled one.
+#[cfg(target_arch = "xtensa")]
+fn current_interrupt() -> u32 {
+    let pending = xtensa_lx::interrupt::get() & xtensa_lx::interrupt::get_mask();
+    if pending == 0 {
+        0
+    } else {
+        31 - pending.leading_zeros()
+    }
+}
+
+/// How to set up the trace channel
+///
+/// The channel is always up channel 0 named `Xray` of a control block holding just this channel -
+/// `rtt_init!` takes the name as a literal only. To use another number or name set up RTT in the
+/// application and hand the channel over with [set_channel] (`external-rtt` feature).
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// What to do when the RTT buffer is full
+    ///
+    /// `NoBlockSkip` drops events and reports them as overflow. `BlockIfFull` waits for the
+    /// host to make room, so no event is lost but the application stalls without a host.
+    pub mode: ChannelMode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { mode: NoBlockSkip }
     }
-    buffer[count] = value as u8;
-    count += 1;
+}
+
+/// Size of the RTT up channel, selected by the `buffer-size-*` features
+#[cfg(not(feature = "external-rtt"))]
+const BUFFER_SIZE: usize = if cfg!(feature = "buffer-size-16384") {
+    16384
+} else if cfg!(feature = "buffer-size-8192") {
+    8192
+} else if cfg!(feature = "buffer-size-4096") {
+    4096
+} else if cfg!(feature = "buffer-size-2048") {
+    2048
+} else {
+    1024
+};
 
-    count
+/// Set up the trace channel
+///
+/// Without calling this the channel is set up with the default config on the first event.
+/// With the `external-rtt` feature the config applies to the channel passed to [set_channel],
+/// no matter which one is called first.
+pub fn init(config: Config) {
+    critical_section::with(|cs| {
+        CONFIG.replace(cs, config);
+        if let Some(mut channel) = channel(cs) {
+            channel.set_mode(config.mode);
+        }
+    });
 }
 
-fn post(data: &[u8]) {
+/// Trace to an RTT channel created by the application
+///
+/// Use this with the `external-rtt` feature when the application already has an RTT control
+/// block, e.g. from `defmt-rtt` or `rtt_init_print!`. Name the channel `Xray` so the server
+/// finds it. Events traced before this is called are reported as overflow.
+pub fn set_channel(mut channel: UpChannel) {
     critical_section::with(|cs| {
-        if CHANNEL.borrow_ref_mut(cs).is_none() {
-            let channels = rtt_init! {
-                up: {
-                    0: {
-                        size: 1024,
-                        mode: NoBlockSkip,
-                        name: "Xray"
-                    }
+        channel.set_mode(CONFIG.borrow_ref(cs).mode);
+        write_frame(&mut channel, &system_info(*CPU_FREQUENCY.borrow_ref(cs)));
+        CHANNEL.borrow_ref_mut(cs).replace(channel);
+    });
+}
+
+/// The trace channel - sets up RTT the first time unless the `external-rtt` feature is enabled
+fn channel(cs: CriticalSection<'_>) -> Option<RefMut<'_, UpChannel>> {
+    #[cfg(not(feature = "external-rtt"))]
+    if CHANNEL.borrow_ref(cs).is_none() {
+        let channels = rtt_target::rtt_init! {
+            up: {
+                0: {
+                    size: BUFFER_SIZE,
+                    mode: NoBlockSkip,
+                    name: "Xray"
                 }
+            }
+        };
+        let mut channel = channels.up.0;
+        channel.set_mode(CONFIG.borrow_ref(cs).mode);
+
+        write_frame(&mut channel, &system_info(*CPU_FREQUENCY.borrow_ref(cs)));
+        CHANNEL.borrow_ref_mut(cs).replace(channel);
+    }
+
+    RefMut::filter_map(CHANNEL.borrow_ref_mut(cs), Option::as_mut).ok()
+}
+
+fn post(event: &Event<&str>) {
+    critical_section::with(|cs| {
+        let mut dropped = DROPPED.borrow_ref_mut(cs);
+        let Some(mut channel) = channel(cs) else {
+            dropped.add(event);
+            return;
+        };
+
+        // report lost events before anything else gets through
+        if dropped.events > 0 {
+            let overflow = Event::Overflow {
+                dropped: dropped.events,
+                ts_delta: dropped.ts_delta,
             };
-            CHANNEL.borrow_ref_mut(cs).replace(channels.up.0);
+            if !write_frame(&mut channel, &overflow) {
+                dropped.add(event);
+                return;
+            }
+            *dropped = Dropped::default();
+        }
+
+        if !write_frame(&mut channel, event) {
+            dropped.add(event);
         }
-        let mut channel = CHANNEL.borrow_ref_mut(cs);
-        let channel = channel.as_mut().unwrap();
-        channel.write(data);
     });
 }
+
+/// Events which didn't fit into the RTT buffer
+#[derive(Default)]
+struct Dropped {
+    events: u32,
+    /// Keeps the timestamps of the following events right
+    ts_delta: u32,
+}
+
+impl Dropped {
+    fn add(&mut self, event: &Event<&str>) {
+        self.events += 1;
+        self.ts_delta = self.ts_delta.wrapping_add(event.ts_delta());
+    }
+}
+
+/// Returns false if there was no room for the whole frame
+fn write_frame(channel: &mut UpChannel, event: &Event<&str>) -> bool {
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    let len = event.encode_frame(&mut frame).unwrap();
+
+    channel.write(&frame[..len]) == len
+}
@@ -10,7 +10,6 @@ use esp_hal::{
     clock::ClockControl, peripherals::Peripherals, prelude::*, system::SystemControl,
     timer::timg::TimerGroup,
 };
-use esp_xray as _;
 
 #[embassy_executor::task]
 async fn run() {
@@ -28,6 +27,7 @@ async fn main(spawner: Spawner) {
     let peripherals = Peripherals::take();
     let system = SystemControl::new(peripherals.SYSTEM);
     let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+    esp_xray::set_clocks(&clocks);
 
     let mut rng = esp_hal::rng::Rng::new(peripherals.RNG);
 
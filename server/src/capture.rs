@@ -0,0 +1,218 @@
+//! Captures of the bytes read from the trace channel
+//!
+//! A capture starts with [MAGIC] and a text header of `key: value` lines (preceded by its length
+//! as `u32`). Every read from the target follows as the time since the capture started in
+//! microseconds (`u64`), the length (`u32`) and the data - all little endian.
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Identifies a capture and the version of the format
+pub const MAGIC: &[u8; 8] = b"XRAYCAP\x01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not a capture or a newer version of the format
+    Magic,
+    /// The header is cut off or not UTF-8
+    Header,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Magic => write!(f, "not an esp-xray capture"),
+            Error::Header => write!(f, "invalid capture header"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Where a capture was taken
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub chip: String,
+    pub probe: String,
+    /// SHA-256 of the firmware image
+    pub elf_sha256: Option<String>,
+    pub server_version: String,
+}
+
+impl Metadata {
+    fn encode(&self) -> String {
+        let mut header = format!("chip: {}\nprobe: {}\n", self.chip, self.probe);
+        if let Some(hash) = &self.elf_sha256 {
+            header += &format!("elf-sha256: {hash}\n");
+        }
+        header += &format!("server-version: {}\n", self.server_version);
+        header
+    }
+
+    /// Unknown keys are ignored
+    fn decode(header: &str) -> Self {
+        let mut this = Self::default();
+        for (key, value) in header.lines().filter_map(|line| line.split_once(": ")) {
+            match key {
+                "chip" => this.chip = value.to_string(),
+                "probe" => this.probe = value.to_string(),
+                "elf-sha256" => this.elf_sha256 = Some(value.to_string()),
+                "server-version" => this.server_version = value.to_string(),
+                _ => log::debug!("Ignoring {key} in capture header"),
+            }
+        }
+        this
+    }
+}
+
+/// Data read from the target at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    /// Time since the capture started
+    pub time: Duration,
+    pub data: &'a [u8],
+}
+
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+    start: Instant,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(mut writer: W, metadata: &Metadata) -> std::io::Result<Self> {
+        let header = metadata.encode();
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Record what was just read from the target - empty reads are skipped
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let time = self.start.elapsed().as_micros() as u64;
+        self.writer.write_all(&time.to_le_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[derive(Debug)]
+pub struct RawCapture<'a> {
+    pub metadata: Metadata,
+    pub chunks: Vec<Chunk<'a>>,
+}
+
+impl<'a> RawCapture<'a> {
+    /// A chunk cut off at the end (e.g. when the server got killed) is dropped
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        let data = data.strip_prefix(MAGIC).ok_or(Error::Magic)?;
+
+        let len = u32::from_le_bytes(data.get(..4).ok_or(Error::Header)?.try_into().unwrap());
+        let header = data.get(4..4 + len as usize).ok_or(Error::Header)?;
+        let metadata = Metadata::decode(std::str::from_utf8(header).map_err(|_| Error::Header)?);
+
+        let mut chunks = Vec::new();
+        let mut rest = &data[4 + len as usize..];
+        while !rest.is_empty() {
+            let Some(chunk) = Self::chunk(rest) else {
+                log::warn!("Ignoring incomplete data at the end of the capture");
+                break;
+            };
+            rest = &rest[12 + chunk.data.len()..];
+            chunks.push(chunk);
+        }
+
+        Ok(Self { metadata, chunks })
+    }
+
+    fn chunk(data: &'a [u8]) -> Option<Chunk<'a>> {
+        let time = u64::from_le_bytes(data.get(..8)?.try_into().unwrap());
+        let len = u32::from_le_bytes(data.get(8..12)?.try_into().unwrap());
+
+        Some(Chunk {
+            time: Duration::from_micros(time),
+            data: data.get(12..12 + len as usize)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            chip: "esp32c6".to_string(),
+            probe: "ESP JTAG".to_string(),
+            elf_sha256: Some("00ff".to_string()),
+            server_version: "0.1.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut writer = CaptureWriter::new(Vec::new(), &metadata()).unwrap();
+        writer.write(&[0xa5, 0x01]).unwrap();
+        writer.write(&[]).unwrap();
+        writer.write(&[0x06, 0x05, 0x0c]).unwrap();
+        let data = writer.into_inner();
+
+        let capture = RawCapture::parse(&data).unwrap();
+
+        assert_eq!(metadata(), capture.metadata);
+        assert_eq!(2, capture.chunks.len());
+        assert_eq!(&[0xa5, 0x01], capture.chunks[0].data);
+        assert_eq!(&[0x06, 0x05, 0x0c], capture.chunks[1].data);
+        assert!(capture.chunks[0].time <= capture.chunks[1].time);
+    }
+
+    #[test]
+    fn test_header() {
+        let header = "chip: esp32\nprobe: ESP JTAG\nserver-version: 0.2.0\nfoo: bar\n";
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        data.extend_from_slice(header.as_bytes());
+
+        let capture = RawCapture::parse(&data).unwrap();
+
+        assert_eq!("esp32", capture.metadata.chip);
+        assert_eq!(None, capture.metadata.elf_sha256);
+        assert_eq!("0.2.0", capture.metadata.server_version);
+        assert!(capture.chunks.is_empty());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut writer = CaptureWriter::new(Vec::new(), &metadata()).unwrap();
+        writer.write(&[0xa5, 0x01]).unwrap();
+        writer.write(&[0x06, 0x05, 0x0c]).unwrap();
+        let data = writer.into_inner();
+
+        let capture = RawCapture::parse(&data[..data.len() - 1]).unwrap();
+        assert_eq!(1, capture.chunks.len());
+
+        assert_eq!(Error::Header, RawCapture::parse(&data[..12]).unwrap_err());
+    }
+
+    #[test]
+    fn test_not_a_capture() {
+        assert_eq!(Error::Magic, RawCapture::parse(b";\n").unwrap_err());
+        assert_eq!(Error::Magic, RawCapture::parse(b"XRAYCAP\x02").unwrap_err());
+    }
+}
@@ -0,0 +1,532 @@
+//! Export to the Chrome trace-event format - opens in ui.perfetto.dev and chrome://tracing
+//!
+//! Every task gets a track showing when it was ready and running, every ISR gets a track and idle
+//! time is shown on a track of its own. Markers are nested into the slice they began in. When a
+//! task stops running its open markers are closed and reopened the next time it runs.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde_json::{json, Value};
+
+use crate::export::{Exporter, Timeline};
+use crate::{Message, SystemInfo};
+
+const PID: u64 = 1;
+const IDLE_TID: u64 = 0;
+/// Track for markers outside of tasks, ISRs and idle time
+const MARKER_TID: u64 = 1;
+/// Tasks and ISRs get tracks in the order they show up, starting here
+///
+/// Task ids are addresses - too large for the 32 bit thread ids Perfetto reads.
+const FIRST_TID: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Track {
+    Task(u32),
+    Isr(u8),
+}
+
+pub struct ChromeTrace {
+    sys_freq: u32,
+    timeline: Timeline,
+    events: Vec<Value>,
+    tasks: BTreeMap<u32, String>,
+    isrs: BTreeSet<u8>,
+    tids: HashMap<Track, u64>,
+    next_tid: u64,
+    running: Option<u32>,
+    /// Tasks which are ready but didn't run yet
+    ready: BTreeSet<u32>,
+    /// Nested ISRs, innermost last
+    isr: Vec<u8>,
+    idle: bool,
+    /// Markers begun on a track, innermost last
+    open_markers: HashMap<u64, Vec<u32>>,
+}
+
+impl ChromeTrace {
+    pub fn new(info: SystemInfo) -> Self {
+        Self {
+            sys_freq: info.sys_freq,
+            timeline: Timeline::default(),
+            events: Vec::new(),
+            tasks: BTreeMap::new(),
+            isrs: BTreeSet::new(),
+            tids: HashMap::new(),
+            next_tid: FIRST_TID,
+            running: None,
+            ready: BTreeSet::new(),
+            isr: Vec::new(),
+            idle: false,
+            open_markers: HashMap::new(),
+        }
+    }
+
+    /// Microseconds since the trace started
+    fn ts(&self) -> f64 {
+        self.timeline.ticks as f64 * 1_000_000.0 / self.sys_freq as f64
+    }
+
+    /// Tasks not named yet are named after their id
+    fn task_name(&mut self, task: u32) -> String {
+        self.tasks
+            .entry(task)
+            .or_insert_with(|| default_task_name(task))
+            .clone()
+    }
+
+    fn task_tid(&mut self, task: u32) -> u64 {
+        self.tid(Track::Task(task))
+    }
+
+    fn isr_tid(&mut self, isr: u8) -> u64 {
+        self.tid(Track::Isr(isr))
+    }
+
+    fn tid(&mut self, track: Track) -> u64 {
+        *self.tids.entry(track).or_insert_with(|| {
+            let tid = self.next_tid;
+            self.next_tid += 1;
+            tid
+        })
+    }
+
+    /// Track of what is executing right now
+    fn current(&mut self) -> u64 {
+        match (self.isr.last().copied(), self.running, self.idle) {
+            (Some(isr), _, _) => self.isr_tid(isr),
+            (None, Some(task), _) => self.task_tid(task),
+            (None, None, true) => IDLE_TID,
+            (None, None, false) => MARKER_TID,
+        }
+    }
+
+    fn begin(&mut self, tid: u64, name: &str) {
+        self.events.push(json!({
+            "name": name,
+            "ph": "B",
+            "ts": self.ts(),
+            "pid": PID,
+            "tid": tid,
+        }));
+    }
+
+    fn end(&mut self, tid: u64) {
+        self.events.push(json!({
+            "ph": "E",
+            "ts": self.ts(),
+            "pid": PID,
+            "tid": tid,
+        }));
+    }
+
+    /// `scope` is `t` for the track, `g` for the whole trace
+    fn instant(&mut self, tid: u64, name: &str, scope: &str) {
+        self.events.push(json!({
+            "name": name,
+            "ph": "i",
+            "s": scope,
+            "ts": self.ts(),
+            "pid": PID,
+            "tid": tid,
+        }));
+    }
+
+    /// Something starts executing - reopen the markers it left open
+    fn begin_context(&mut self, tid: u64, name: &str) {
+        self.begin(tid, name);
+
+        let markers = self.open_markers.get(&tid).cloned().unwrap_or_default();
+        for id in markers {
+            let name = self.timeline.marker_name(id);
+            self.begin(tid, &name);
+        }
+    }
+
+    /// Something stops executing - close its markers first to keep the slices nested
+    fn end_context(&mut self, tid: u64) {
+        let open = self.open_markers.get(&tid).map_or(0, Vec::len);
+        for _ in 0..open {
+            self.end(tid);
+        }
+
+        self.end(tid);
+    }
+
+    fn is_executing(&self, tid: u64) -> bool {
+        let is_track = |track| self.tids.get(&track) == Some(&tid);
+
+        tid == MARKER_TID
+            || (tid == IDLE_TID && self.idle)
+            || self.running.is_some_and(|task| is_track(Track::Task(task)))
+            || self.isr.iter().any(|isr| is_track(Track::Isr(*isr)))
+    }
+
+    fn end_marker(&mut self, id: u32) {
+        let current = self.current();
+        let tid = match self.open_markers.get(&current) {
+            Some(markers) if markers.contains(&id) => current,
+            _ => {
+                let Some((tid, _)) = self
+                    .open_markers
+                    .iter()
+                    .find(|(_, markers)| markers.contains(&id))
+                else {
+                    log::debug!("Marker {id} ends without having begun");
+                    return;
+                };
+                *tid
+            }
+        };
+
+        let markers = self.open_markers.get_mut(&tid).unwrap();
+        let index = markers.iter().rposition(|marker| *marker == id).unwrap();
+        let inner = markers.split_off(index + 1);
+        markers.pop();
+
+        if !self.is_executing(tid) {
+            // closed when its task stopped running
+            self.open_markers.get_mut(&tid).unwrap().extend(inner);
+            return;
+        }
+
+        // the markers begun after this one end and begin again
+        for _ in 0..=inner.len() {
+            self.end(tid);
+        }
+        for id in inner {
+            let name = self.timeline.marker_name(id);
+            self.begin(tid, &name);
+            self.open_markers.get_mut(&tid).unwrap().push(id);
+        }
+    }
+}
+
+impl Exporter for ChromeTrace {
+    type Output = Value;
+
+    fn name_task(&mut self, task: u32, name: &str) {
+        self.tasks.insert(task, name.to_string());
+    }
+
+    fn push(&mut self, message: &Message) {
+        self.timeline.push(message);
+
+        match *message {
+            Message::TaskNew(task, _) => {
+                self.task_name(task);
+            }
+            Message::TaskInfo(task, _, name, _, _, _) => self.name_task(task, name),
+            Message::TaskReadyBegin(task, _) => {
+                if self.running != Some(task) && self.ready.insert(task) {
+                    self.task_name(task);
+                    let tid = self.task_tid(task);
+                    self.begin(tid, "ready");
+                }
+            }
+            Message::TaskReadyEnd(task, _) => {
+                if self.ready.remove(&task) {
+                    let tid = self.task_tid(task);
+                    self.end(tid);
+                }
+            }
+            Message::TaskExecBegin(task, _) => {
+                if self.idle {
+                    self.idle = false;
+                    self.end_context(IDLE_TID);
+                }
+                if let Some(running) = self.running.take() {
+                    let tid = self.task_tid(running);
+                    self.end_context(tid);
+                }
+                let tid = self.task_tid(task);
+                if self.ready.remove(&task) {
+                    self.end(tid);
+                }
+
+                self.running = Some(task);
+                let name = self.task_name(task);
+                self.begin_context(tid, &name);
+            }
+            Message::TaskExecEnd(_) => {
+                if let Some(running) = self.running.take() {
+                    let tid = self.task_tid(running);
+                    self.end_context(tid);
+                }
+            }
+            Message::TaskTerminate(task, _) => {
+                let tid = self.task_tid(task);
+                if self.running == Some(task) {
+                    self.running = None;
+                    self.end_context(tid);
+                }
+                if self.ready.remove(&task) {
+                    self.end(tid);
+                }
+                self.open_markers.remove(&tid);
+                self.instant(tid, "terminated", "t");
+            }
+            Message::SystemIdle(_) => {
+                if let Some(running) = self.running.take() {
+                    let tid = self.task_tid(running);
+                    self.end_context(tid);
+                }
+                if !self.idle {
+                    self.idle = true;
+                    self.begin_context(IDLE_TID, "idle");
+                }
+            }
+            Message::IsrEnter(isr, _) => {
+                self.isrs.insert(isr);
+                self.isr.push(isr);
+                let tid = self.isr_tid(isr);
+                self.begin_context(tid, &format!("ISR {isr}"));
+            }
+            Message::IsrExit(_) | Message::IsrToScheduler(_) => {
+                if let Some(isr) = self.isr.pop() {
+                    let tid = self.isr_tid(isr);
+                    self.end_context(tid);
+                }
+            }
+            Message::Marker(id, _) => {
+                let name = self.timeline.marker_name(id);
+                let tid = self.current();
+                self.instant(tid, &name, "t");
+            }
+            Message::MarkerBegin(id, _) => {
+                let tid = self.current();
+                self.open_markers.entry(tid).or_default().push(id);
+                let name = self.timeline.marker_name(id);
+                self.begin(tid, &name);
+            }
+            Message::MarkerEnd(id, _) => self.end_marker(id),
+            Message::Overflow(dropped, _) => {
+                self.instant(MARKER_TID, &format!("{dropped} events lost"), "g");
+            }
+            Message::SystemInfo(info) => self.sys_freq = info.sys_freq,
+            Message::MarkerName(..) | Message::Disconnect(_) => (),
+        }
+    }
+
+    fn finish(mut self) -> Value {
+        while let Some(isr) = self.isr.pop() {
+            let tid = self.isr_tid(isr);
+            self.end_context(tid);
+        }
+        if let Some(running) = self.running.take() {
+            let tid = self.task_tid(running);
+            self.end_context(tid);
+        }
+        if self.idle {
+            self.end_context(IDLE_TID);
+        }
+        for task in std::mem::take(&mut self.ready) {
+            let tid = self.task_tid(task);
+            self.end(tid);
+        }
+        for _ in self.open_markers.remove(&MARKER_TID).unwrap_or_default() {
+            self.end(MARKER_TID);
+        }
+
+        let mut tracks = vec![(IDLE_TID, "Idle".to_string())];
+        for (task, name) in self.tasks.clone() {
+            // the track ids aren't telling - keep the address of named tasks
+            let name = if name == default_task_name(task) {
+                name
+            } else {
+                format!("{name} ({task:#010x})")
+            };
+            tracks.push((self.task_tid(task), name));
+        }
+        for isr in self.isrs.clone() {
+            tracks.push((self.isr_tid(isr), format!("ISR {isr}")));
+        }
+        tracks.push((MARKER_TID, "Markers".to_string()));
+
+        let mut events = vec![json!({
+            "name": "process_name",
+            "ph": "M",
+            "pid": PID,
+            "args": { "name": "esp-xray" },
+        })];
+        for (index, (tid, name)) in tracks.into_iter().enumerate() {
+            events.push(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": PID,
+                "tid": tid,
+                "args": { "name": name },
+            }));
+            events.push(json!({
+                "name": "thread_sort_index",
+                "ph": "M",
+                "pid": PID,
+                "tid": tid,
+                "args": { "sort_index": index },
+            }));
+        }
+        events.append(&mut self.events);
+
+        json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ns",
+        })
+    }
+}
+
+fn default_task_name(task: u32) -> String {
+    format!("Task {task:#010x}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::export::fixtures::{INFO, TASK};
+
+    /// Phase, track and name of the slice events
+    fn slices(trace: &Value) -> Vec<(String, u64, Option<String>, f64)> {
+        trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["ph"] != "M")
+            .map(|event| {
+                (
+                    event["ph"].as_str().unwrap().to_string(),
+                    event["tid"].as_u64().unwrap(),
+                    event["name"].as_str().map(str::to_string),
+                    event["ts"].as_f64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn slice(
+        ph: &str,
+        tid: u64,
+        name: Option<&str>,
+        ts: f64,
+    ) -> (String, u64, Option<String>, f64) {
+        (ph.to_string(), tid, name.map(str::to_string), ts)
+    }
+
+    #[test]
+    fn test_task() {
+        let mut trace = ChromeTrace::new(INFO);
+        for message in [
+            Message::TaskNew(TASK, 0),
+            Message::TaskInfo(TASK, 0, "main", 0, 0, 0),
+            Message::TaskReadyBegin(TASK, 10),
+            Message::TaskExecBegin(TASK, 5),
+            Message::TaskExecEnd(20),
+            Message::SystemIdle(1),
+            Message::TaskExecBegin(TASK, 100),
+        ] {
+            trace.push(&message);
+        }
+        let trace = trace.finish();
+
+        let tid = FIRST_TID;
+        assert_eq!(
+            vec![
+                slice("B", tid, Some("ready"), 10.0),
+                slice("E", tid, None, 15.0),
+                slice("B", tid, Some("main"), 15.0),
+                slice("E", tid, None, 35.0),
+                slice("B", IDLE_TID, Some("idle"), 36.0),
+                slice("E", IDLE_TID, None, 136.0),
+                slice("B", tid, Some("main"), 136.0),
+                slice("E", tid, None, 136.0),
+            ],
+            slices(&trace)
+        );
+
+        let names: Vec<&Value> = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["name"] == "thread_name")
+            .map(|event| &event["args"]["name"])
+            .collect();
+        assert_eq!(vec!["Idle", "main (0x40001000)", "Markers"], names);
+    }
+
+    #[test]
+    fn test_isr() {
+        let mut trace = ChromeTrace::new(INFO);
+        for message in [
+            Message::TaskExecBegin(TASK, 0),
+            Message::IsrEnter(7, 10),
+            Message::MarkerBegin(1, 1),
+            Message::MarkerEnd(1, 1),
+            Message::IsrToScheduler(1),
+            Message::TaskExecEnd(10),
+        ] {
+            trace.push(&message);
+        }
+
+        let (task, isr) = (FIRST_TID, FIRST_TID + 1);
+        assert_eq!(
+            vec![
+                slice("B", task, Some("Task 0x40001000"), 0.0),
+                slice("B", isr, Some("ISR 7"), 10.0),
+                slice("B", isr, Some("Marker 1"), 11.0),
+                slice("E", isr, None, 12.0),
+                slice("E", isr, None, 13.0),
+                slice("E", task, None, 23.0),
+            ],
+            slices(&trace.finish())
+        );
+    }
+
+    #[test]
+    fn test_marker_across_polls() {
+        let mut trace = ChromeTrace::new(INFO);
+        for message in [
+            Message::MarkerName(1, "outer", 0),
+            Message::TaskExecBegin(TASK, 0),
+            Message::MarkerBegin(1, 1),
+            Message::MarkerBegin(2, 1),
+            Message::TaskExecEnd(1),
+            Message::TaskExecBegin(TASK, 1),
+            Message::MarkerEnd(1, 1),
+            Message::MarkerEnd(2, 1),
+            Message::TaskExecEnd(1),
+        ] {
+            trace.push(&message);
+        }
+
+        let tid = FIRST_TID;
+        assert_eq!(
+            vec![
+                slice("B", tid, Some("Task 0x40001000"), 0.0),
+                slice("B", tid, Some("outer"), 1.0),
+                slice("B", tid, Some("Marker 2"), 2.0),
+                slice("E", tid, None, 3.0),
+                slice("E", tid, None, 3.0),
+                slice("E", tid, None, 3.0),
+                slice("B", tid, Some("Task 0x40001000"), 4.0),
+                slice("B", tid, Some("outer"), 4.0),
+                slice("B", tid, Some("Marker 2"), 4.0),
+                // outer ends, marker 2 continues
+                slice("E", tid, None, 5.0),
+                slice("E", tid, None, 5.0),
+                slice("B", tid, Some("Marker 2"), 5.0),
+                slice("E", tid, None, 6.0),
+                slice("E", tid, None, 7.0),
+            ],
+            slices(&trace.finish())
+        );
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut trace = ChromeTrace::new(INFO);
+        trace.push(&Message::Overflow(3, 2));
+
+        assert_eq!(
+            vec![slice("i", MARKER_TID, Some("3 events lost"), 2.0)],
+            slices(&trace.finish())
+        );
+    }
+}
@@ -0,0 +1,556 @@
+//! Export to the Common Trace Format (CTF 1.8) - for Trace Compass and babeltrace
+//!
+//! The events are laid out like the ones of an LTTng kernel trace so Trace Compass' scheduler
+//! views work: tasks are threads, the idle time is the swapper (tid 0) and ISRs are IRQs. Markers
+//! and overflows get events of their own.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::export::{Exporter, Timeline};
+use crate::{Message, SystemInfo};
+
+const MAGIC: u32 = 0xc1fc_1fc1;
+
+/// Packets are closed once their events take this many bytes
+const MAX_PACKET_EVENTS: usize = 64 * 1024;
+
+const PACKET_HEADER_LEN: usize = 4 + 4;
+const PACKET_CONTEXT_LEN: usize = 8 * 5 + 4;
+
+const SCHED_SWITCH: u32 = 0;
+const SCHED_WAKEUP: u32 = 1;
+const IRQ_HANDLER_ENTRY: u32 = 2;
+const IRQ_HANDLER_EXIT: u32 = 3;
+const SCHED_PROCESS_FREE: u32 = 4;
+const MARKER: u32 = 5;
+const MARKER_BEGIN: u32 = 6;
+const MARKER_END: u32 = 7;
+const OVERFLOW: u32 = 8;
+
+/// `prev_state` of a task which stopped running
+const TASK_RUNNING: i64 = 0;
+const TASK_INTERRUPTIBLE: i64 = 1;
+
+/// `ret` of `irq_handler_exit`
+const IRQ_HANDLED: i32 = 1;
+
+const METADATA_HEADER: &str = r#"/* CTF 1.8 */
+
+typealias integer { size = 8; align = 8; signed = false; } := uint8_t;
+typealias integer { size = 32; align = 8; signed = false; } := uint32_t;
+typealias integer { size = 64; align = 8; signed = false; } := uint64_t;
+typealias integer { size = 32; align = 8; signed = true; } := int32_t;
+typealias integer { size = 64; align = 8; signed = true; } := int64_t;
+
+trace {
+    major = 1;
+    minor = 8;
+    byte_order = le;
+    packet.header := struct {
+        uint32_t magic;
+        uint32_t stream_id;
+    };
+};
+
+env {
+    domain = "kernel";
+    tracer_name = "lttng-modules";
+    tracer_major = 2;
+    tracer_minor = 13;
+    hostname = "esp-xray";
+    sysname = "esp-xray";
+};
+"#;
+
+const METADATA_STREAM: &str = r#"
+typealias integer { size = 64; align = 8; signed = false; map = clock.monotonic.value; } := uint64_clock_monotonic_t;
+
+struct packet_context {
+    uint64_clock_monotonic_t timestamp_begin;
+    uint64_clock_monotonic_t timestamp_end;
+    uint64_t content_size;
+    uint64_t packet_size;
+    uint64_t events_discarded;
+    uint32_t cpu_id;
+};
+
+struct event_header {
+    uint32_t id;
+    uint64_clock_monotonic_t timestamp;
+};
+
+stream {
+    id = 0;
+    event.header := struct event_header;
+    packet.context := struct packet_context;
+};
+
+event {
+    name = "sched_switch";
+    id = 0;
+    stream_id = 0;
+    fields := struct {
+        integer { size = 8; align = 8; signed = true; encoding = UTF8; base = 10; } _prev_comm[16];
+        int32_t _prev_tid;
+        int32_t _prev_prio;
+        int64_t _prev_state;
+        integer { size = 8; align = 8; signed = true; encoding = UTF8; base = 10; } _next_comm[16];
+        int32_t _next_tid;
+        int32_t _next_prio;
+    };
+};
+
+event {
+    name = "sched_wakeup";
+    id = 1;
+    stream_id = 0;
+    fields := struct {
+        integer { size = 8; align = 8; signed = true; encoding = UTF8; base = 10; } _comm[16];
+        int32_t _tid;
+        int32_t _prio;
+        int32_t _target_cpu;
+    };
+};
+
+event {
+    name = "irq_handler_entry";
+    id = 2;
+    stream_id = 0;
+    fields := struct {
+        int32_t _irq;
+        string _name;
+    };
+};
+
+event {
+    name = "irq_handler_exit";
+    id = 3;
+    stream_id = 0;
+    fields := struct {
+        int32_t _irq;
+        int32_t _ret;
+    };
+};
+
+event {
+    name = "sched_process_free";
+    id = 4;
+    stream_id = 0;
+    fields := struct {
+        integer { size = 8; align = 8; signed = true; encoding = UTF8; base = 10; } _comm[16];
+        int32_t _tid;
+        int32_t _prio;
+    };
+};
+
+event {
+    name = "esp_xray_marker";
+    id = 5;
+    stream_id = 0;
+    fields := struct {
+        uint32_t _id;
+        string _name;
+    };
+};
+
+event {
+    name = "esp_xray_marker_begin";
+    id = 6;
+    stream_id = 0;
+    fields := struct {
+        uint32_t _id;
+        string _name;
+    };
+};
+
+event {
+    name = "esp_xray_marker_end";
+    id = 7;
+    stream_id = 0;
+    fields := struct {
+        uint32_t _id;
+        string _name;
+    };
+};
+
+event {
+    name = "esp_xray_overflow";
+    id = 8;
+    stream_id = 0;
+    fields := struct {
+        uint32_t _dropped;
+    };
+};
+"#;
+
+/// A task as seen by Trace Compass
+#[derive(Debug, Clone)]
+struct Thread {
+    tid: i32,
+    name: String,
+    prio: i32,
+}
+
+/// A CTF trace - the `metadata` and one stream
+pub struct Ctf {
+    pub metadata: String,
+    pub stream: Vec<u8>,
+}
+
+impl Ctf {
+    /// Write the trace to the directory `dir` - it's created if needed
+    pub fn write(&self, dir: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("metadata"), &self.metadata)?;
+        std::fs::write(dir.join("channel0_0"), &self.stream)
+    }
+}
+
+pub struct CtfTrace {
+    sys_freq: u32,
+    timeline: Timeline,
+    threads: HashMap<u32, Thread>,
+    next_tid: i32,
+    /// The running task - the swapper if none
+    current: Option<u32>,
+    /// Nested ISRs, innermost last
+    isr: Vec<u8>,
+    stream: Vec<u8>,
+    /// Events of the packet being written
+    packet: Vec<u8>,
+    packet_begin: u64,
+    discarded: u64,
+}
+
+impl CtfTrace {
+    pub fn new(info: SystemInfo) -> Self {
+        Self {
+            sys_freq: info.sys_freq,
+            timeline: Timeline::default(),
+            threads: HashMap::new(),
+            next_tid: 1,
+            current: None,
+            isr: Vec::new(),
+            stream: Vec::new(),
+            packet: Vec::new(),
+            packet_begin: 0,
+            discarded: 0,
+        }
+    }
+
+    /// Tasks get thread ids in the order they show up - 0 is the swapper
+    fn thread(&mut self, task: u32) -> &mut Thread {
+        self.threads.entry(task).or_insert_with(|| {
+            let tid = self.next_tid;
+            self.next_tid += 1;
+            Thread {
+                tid,
+                name: format!("task-{task:08x}"),
+                prio: 0,
+            }
+        })
+    }
+
+    fn swapper() -> Thread {
+        Thread {
+            tid: 0,
+            name: "swapper".to_string(),
+            prio: 0,
+        }
+    }
+
+    fn current_thread(&mut self) -> Thread {
+        match self.current {
+            Some(task) => self.thread(task).clone(),
+            None => Self::swapper(),
+        }
+    }
+
+    fn switch(&mut self, prev: &Thread, prev_state: i64, next: &Thread) {
+        let mut event = comm(&prev.name).to_vec();
+        event.extend_from_slice(&prev.tid.to_le_bytes());
+        event.extend_from_slice(&prev.prio.to_le_bytes());
+        event.extend_from_slice(&prev_state.to_le_bytes());
+        event.extend_from_slice(&comm(&next.name));
+        event.extend_from_slice(&next.tid.to_le_bytes());
+        event.extend_from_slice(&next.prio.to_le_bytes());
+        self.event(SCHED_SWITCH, &event);
+    }
+
+    fn marker(&mut self, event: u32, marker: u32) {
+        let name = self.timeline.marker_name(marker);
+
+        let mut fields = marker.to_le_bytes().to_vec();
+        fields.extend_from_slice(name.replace('\0', "").as_bytes());
+        fields.push(0);
+        self.event(event, &fields);
+    }
+
+    fn event(&mut self, id: u32, fields: &[u8]) {
+        if self.packet.is_empty() {
+            self.packet_begin = self.timeline.ticks;
+        }
+
+        self.packet.extend_from_slice(&id.to_le_bytes());
+        self.packet
+            .extend_from_slice(&self.timeline.ticks.to_le_bytes());
+        self.packet.extend_from_slice(fields);
+
+        if self.packet.len() >= MAX_PACKET_EVENTS {
+            self.close_packet();
+        }
+    }
+
+    fn close_packet(&mut self) {
+        if self.packet.is_empty() {
+            return;
+        }
+
+        let bits = ((PACKET_HEADER_LEN + PACKET_CONTEXT_LEN + self.packet.len()) * 8) as u64;
+
+        self.stream.extend_from_slice(&MAGIC.to_le_bytes());
+        self.stream.extend_from_slice(&0u32.to_le_bytes());
+
+        self.stream
+            .extend_from_slice(&self.packet_begin.to_le_bytes());
+        self.stream
+            .extend_from_slice(&self.timeline.ticks.to_le_bytes());
+        self.stream.extend_from_slice(&bits.to_le_bytes());
+        self.stream.extend_from_slice(&bits.to_le_bytes());
+        self.stream.extend_from_slice(&self.discarded.to_le_bytes());
+        self.stream.extend_from_slice(&0u32.to_le_bytes());
+
+        self.stream.append(&mut self.packet);
+    }
+}
+
+impl Exporter for CtfTrace {
+    type Output = Ctf;
+
+    fn name_task(&mut self, task: u32, name: &str) {
+        self.thread(task).name = name.to_string();
+    }
+
+    fn push(&mut self, message: &Message) {
+        self.timeline.push(message);
+
+        match *message {
+            Message::TaskNew(task, _) => {
+                self.thread(task);
+            }
+            Message::TaskInfo(task, prio, name, _, _, _) => {
+                let thread = self.thread(task);
+                thread.name = name.to_string();
+                thread.prio = prio as i32;
+            }
+            Message::TaskExecBegin(task, _) => {
+                let prev = self.current_thread();
+                let next = self.thread(task).clone();
+                self.switch(&prev, TASK_RUNNING, &next);
+                self.current = Some(task);
+            }
+            Message::TaskExecEnd(_) | Message::SystemIdle(_) => {
+                if self.current.is_some() {
+                    let prev = self.current_thread();
+                    self.switch(&prev, TASK_INTERRUPTIBLE, &Self::swapper());
+                    self.current = None;
+                }
+            }
+            Message::TaskReadyBegin(task, _) => {
+                let thread = self.thread(task).clone();
+                let mut event = comm(&thread.name).to_vec();
+                event.extend_from_slice(&thread.tid.to_le_bytes());
+                event.extend_from_slice(&thread.prio.to_le_bytes());
+                event.extend_from_slice(&0i32.to_le_bytes());
+                self.event(SCHED_WAKEUP, &event);
+            }
+            Message::TaskReadyEnd(_, _) => (),
+            Message::TaskTerminate(task, _) => {
+                if self.current == Some(task) {
+                    let prev = self.current_thread();
+                    self.switch(&prev, TASK_INTERRUPTIBLE, &Self::swapper());
+                    self.current = None;
+                }
+
+                let thread = self.thread(task).clone();
+                let mut event = comm(&thread.name).to_vec();
+                event.extend_from_slice(&thread.tid.to_le_bytes());
+                event.extend_from_slice(&thread.prio.to_le_bytes());
+                self.event(SCHED_PROCESS_FREE, &event);
+                self.threads.remove(&task);
+            }
+            Message::IsrEnter(isr, _) => {
+                self.isr.push(isr);
+                let mut event = (isr as i32).to_le_bytes().to_vec();
+                event.extend_from_slice(format!("ISR {isr}\0").as_bytes());
+                self.event(IRQ_HANDLER_ENTRY, &event);
+            }
+            Message::IsrExit(_) | Message::IsrToScheduler(_) => {
+                if let Some(isr) = self.isr.pop() {
+                    let mut event = (isr as i32).to_le_bytes().to_vec();
+                    event.extend_from_slice(&IRQ_HANDLED.to_le_bytes());
+                    self.event(IRQ_HANDLER_EXIT, &event);
+                }
+            }
+            Message::Marker(id, _) => self.marker(MARKER, id),
+            Message::MarkerBegin(id, _) => self.marker(MARKER_BEGIN, id),
+            Message::MarkerEnd(id, _) => self.marker(MARKER_END, id),
+            Message::Overflow(dropped, _) => {
+                self.discarded += dropped as u64;
+                self.event(OVERFLOW, &dropped.to_le_bytes());
+            }
+            Message::SystemInfo(info) => {
+                if info.sys_freq != self.sys_freq {
+                    log::warn!("Ignoring a change of the timestamp frequency");
+                }
+            }
+            Message::MarkerName(..) | Message::Disconnect(_) => (),
+        }
+    }
+
+    fn finish(mut self) -> Ctf {
+        self.close_packet();
+
+        let clock = format!(
+            "
+clock {{
+    name = \"monotonic\";
+    description = \"esp-xray timestamps\";
+    freq = {};
+    offset = 0;
+}};
+",
+            self.sys_freq
+        );
+
+        Ctf {
+            metadata: format!("{METADATA_HEADER}{clock}{METADATA_STREAM}"),
+            stream: self.stream,
+        }
+    }
+}
+
+/// Thread names are at most 16 bytes including the NUL
+fn comm(name: &str) -> [u8; 16] {
+    let mut comm = [0u8; 16];
+    let mut len = name.len().min(15);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    comm[..len].copy_from_slice(&name.as_bytes()[..len]);
+    comm
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::export::fixtures::{INFO, TASK};
+
+    fn trace(messages: &[Message]) -> Ctf {
+        let mut trace = CtfTrace::new(INFO);
+        for message in messages {
+            trace.push(message);
+        }
+        trace.finish()
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], pos: usize) -> u64 {
+        u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_metadata() {
+        let ctf = trace(&[]);
+
+        assert!(ctf.metadata.starts_with("/* CTF 1.8 */"));
+        assert!(ctf.metadata.contains("freq = 1000000;"));
+        assert!(ctf.stream.is_empty());
+    }
+
+    #[test]
+    fn test_switch() {
+        let ctf = trace(&[
+            Message::TaskNew(TASK, 0),
+            Message::TaskInfo(TASK, 3, "a very long task name", 0, 0, 0),
+            Message::TaskExecBegin(TASK, 100),
+            Message::TaskExecEnd(50),
+        ]);
+        let stream = &ctf.stream;
+
+        let header = PACKET_HEADER_LEN + PACKET_CONTEXT_LEN;
+        let event = 4 + 8 + 16 + 4 + 4 + 8 + 16 + 4 + 4;
+        let bits = (header + 2 * event) as u64 * 8;
+
+        assert_eq!(MAGIC, u32_at(stream, 0));
+        assert_eq!(100, u64_at(stream, 8));
+        assert_eq!(150, u64_at(stream, 16));
+        assert_eq!(bits, u64_at(stream, 24));
+        assert_eq!(bits, u64_at(stream, 32));
+        assert_eq!(stream.len() as u64 * 8, bits);
+
+        // swapper -> task
+        let fields = header + 12;
+        assert_eq!(SCHED_SWITCH, u32_at(stream, header));
+        assert_eq!(100, u64_at(stream, header + 4));
+        assert_eq!(b"swapper\0", &stream[fields..fields + 8]);
+        assert_eq!(0, u32_at(stream, fields + 16));
+        assert_eq!(b"a very long tas\0", &stream[fields + 32..fields + 48]);
+        assert_eq!(1, u32_at(stream, fields + 48));
+        assert_eq!(3, u32_at(stream, fields + 52));
+
+        // task -> swapper
+        let fields = header + event + 12;
+        assert_eq!(150, u64_at(stream, header + event + 4));
+        assert_eq!(1, u32_at(stream, fields + 16));
+        assert_eq!(TASK_INTERRUPTIBLE as u64, u64_at(stream, fields + 24));
+        assert_eq!(0, u32_at(stream, fields + 48));
+    }
+
+    #[test]
+    fn test_marker() {
+        let ctf = trace(&[
+            Message::MarkerName(7, "phase", 0),
+            Message::MarkerBegin(7, 1),
+        ]);
+        let event = PACKET_HEADER_LEN + PACKET_CONTEXT_LEN;
+
+        assert_eq!(MARKER_BEGIN, u32_at(&ctf.stream, event));
+        assert_eq!(7, u32_at(&ctf.stream, event + 12));
+        assert_eq!(b"phase\0", &ctf.stream[event + 16..]);
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut messages = Vec::new();
+        for _ in 0..MAX_PACKET_EVENTS / 16 {
+            messages.push(Message::IsrEnter(1, 1));
+            messages.push(Message::IsrExit(1));
+        }
+        let ctf = trace(&messages);
+
+        let mut packets = 0;
+        let mut pos = 0;
+        while pos < ctf.stream.len() {
+            assert_eq!(MAGIC, u32_at(&ctf.stream, pos));
+            pos += u64_at(&ctf.stream, pos + 32) as usize / 8;
+            packets += 1;
+        }
+
+        assert_eq!(ctf.stream.len(), pos);
+        assert!(packets > 1);
+    }
+
+    #[test]
+    fn test_comm() {
+        assert_eq!(b"main\0\0\0\0\0\0\0\0\0\0\0\0", &comm("main"));
+        assert_eq!(
+            b"\xc3\xa4\xc3\xa4\xc3\xa4\xc3\xa4\xc3\xa4\xc3\xa4\xc3\xa4\0\0",
+            &comm("ääääääääää")
+        );
+    }
+}
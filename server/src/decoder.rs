@@ -0,0 +1,381 @@
+use super::*;
+
+pub use esp_xray_protocol::Event;
+use esp_xray_protocol::{Error as DecodeError, FRAME_OVERHEAD, SYNC};
+
+/// Events read from the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetEvent {
+    Event(Event<String>),
+    /// Data was lost - reported by the decoder, not sent by the target
+    Corrupted {
+        frames: u32,
+    },
+}
+
+impl TargetEvent {
+    pub fn message(&self) -> Message<'_> {
+        let event = match self {
+            TargetEvent::Event(event) => event,
+            TargetEvent::Corrupted { frames } => return Message::Overflow(*frames, 0),
+        };
+
+        match *event {
+            Event::TaskNew { task, ts_delta } => Message::TaskNew(task, ts_delta),
+            Event::TaskExecBegin { task, ts_delta } => Message::TaskExecBegin(task, ts_delta),
+            Event::TaskExecEnd { ts_delta } => Message::TaskExecEnd(ts_delta),
+            Event::TaskReadyBegin { task, ts_delta } => Message::TaskReadyBegin(task, ts_delta),
+            Event::TaskReadyEnd { task, ts_delta } => Message::TaskReadyEnd(task, ts_delta),
+            Event::SystemIdle { ts_delta } => Message::SystemIdle(ts_delta),
+            Event::IsrEnter { isr, ts_delta } => Message::IsrEnter(isr as u8, ts_delta),
+            Event::IsrExit { ts_delta } => Message::IsrExit(ts_delta),
+            Event::IsrExitToScheduler { ts_delta } => Message::IsrToScheduler(ts_delta),
+            Event::TaskInfo {
+                task,
+                prio,
+                stack_base,
+                stack_size,
+                ref name,
+                ts_delta,
+            } => Message::TaskInfo(task, prio, name, stack_base, stack_size, ts_delta),
+            Event::TaskTerminate { task, ts_delta } => Message::TaskTerminate(task, ts_delta),
+            Event::Marker { id, ts_delta } => Message::Marker(id, ts_delta),
+            Event::MarkerBegin { id, ts_delta } => Message::MarkerBegin(id, ts_delta),
+            Event::MarkerEnd { id, ts_delta } => Message::MarkerEnd(id, ts_delta),
+            Event::MarkerName {
+                id,
+                ref name,
+                ts_delta,
+            } => Message::MarkerName(id, name, ts_delta),
+            Event::SystemInfo { .. } => Message::SystemInfo(self.system_info().unwrap()),
+            Event::Overflow { dropped, ts_delta } => Message::Overflow(dropped, ts_delta),
+        }
+    }
+
+    /// The system descriptor, if this is one
+    pub fn system_info(&self) -> Option<SystemInfo> {
+        match *self {
+            TargetEvent::Event(Event::SystemInfo {
+                sys_freq,
+                cpu_freq,
+                ram_base,
+                chip_id,
+            }) => Some(SystemInfo {
+                sys_freq,
+                cpu_freq,
+                ram_base,
+                chip_id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes the byte stream read from the target
+///
+/// Events split across reads are kept until the rest of them arrives. Bytes which aren't a
+/// valid frame are skipped until the next one is found - each such gap is reported as a
+/// `TargetEvent::Corrupted` before the next good event.
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    buffer: Vec<u8>,
+    /// Skipping bytes since the last good frame
+    resyncing: bool,
+    /// Gaps not reported yet
+    unreported: u32,
+    corrupted: u32,
+    next: Option<TargetEvent>,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add bytes read from the target
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Number of corrupted frames seen so far
+    pub fn corrupted(&self) -> u32 {
+        self.corrupted
+    }
+
+    /// Take the next complete event
+    pub fn next_event(&mut self) -> Option<TargetEvent> {
+        if let Some(event) = self.next.take() {
+            return Some(event);
+        }
+
+        loop {
+            match self.buffer.iter().position(|b| *b == SYNC) {
+                Some(0) => (),
+                Some(pos) => {
+                    self.skip(pos);
+                }
+                None => {
+                    let len = self.buffer.len();
+                    self.skip(len);
+                    return None;
+                }
+            }
+
+            let (event, len) = match Event::decode_frame(&self.buffer) {
+                Ok((event, len)) => (TargetEvent::Event(event.map_name(String::from)), len),
+                Err(DecodeError::Incomplete) => return None,
+                Err(DecodeError::UnknownEvent(id)) => {
+                    // the frame is fine - probably sent by a newer target
+                    log::warn!("Unknown event {id} - skipping");
+                    self.buffer
+                        .drain(..self.buffer[1] as usize + FRAME_OVERHEAD);
+                    continue;
+                }
+                Err(_) => {
+                    self.skip(1);
+                    continue;
+                }
+            };
+
+            self.buffer.drain(..len);
+            self.resyncing = false;
+
+            if self.unreported > 0 {
+                let frames = std::mem::take(&mut self.unreported);
+                self.next = Some(event);
+                return Some(TargetEvent::Corrupted { frames });
+            }
+
+            return Some(event);
+        }
+    }
+
+    /// Drop `count` bytes which aren't part of a valid frame
+    fn skip(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+
+        if !self.resyncing {
+            self.resyncing = true;
+            self.unreported += 1;
+            self.corrupted += 1;
+        }
+
+        self.buffer.drain(..count);
+    }
+}
+
+impl Iterator for StreamDecoder {
+    type Item = TargetEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use esp_xray_protocol::{checksum, MAX_FRAME_LEN};
+
+    use super::*;
+
+    fn push(event: Event<&str>, out: &mut Vec<u8>) {
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = event.encode_frame(&mut buffer).unwrap();
+        out.extend_from_slice(&buffer[..len]);
+    }
+
+    fn owned(event: Event<&str>) -> TargetEvent {
+        TargetEvent::Event(event.map_name(String::from))
+    }
+
+    /// A stream like the target would send it, and the events in it
+    fn stream() -> (Vec<u8>, Vec<TargetEvent>) {
+        let events = [
+            Event::SystemInfo {
+                sys_freq: 1_000_000,
+                cpu_freq: 160_000_000,
+                ram_base: 0x4080_0000,
+                chip_id: 13,
+            },
+            Event::TaskNew {
+                task: 0x4080_1234,
+                ts_delta: 0,
+            },
+            Event::TaskInfo {
+                task: 0x4080_1234,
+                prio: 3,
+                stack_base: 0x4080_2000,
+                stack_size: 4096,
+                name: "main",
+                ts_delta: 77,
+            },
+            Event::IsrEnter {
+                isr: 17,
+                ts_delta: 300,
+            },
+            Event::IsrExit { ts_delta: u32::MAX },
+            Event::TaskReadyEnd {
+                task: 0x4080_1234,
+                ts_delta: 128,
+            },
+        ];
+
+        let mut data = Vec::new();
+        for event in events {
+            push(event, &mut data);
+        }
+
+        (data, events.into_iter().map(owned).collect())
+    }
+
+    fn decode_chunks(chunks: &[&[u8]]) -> Vec<TargetEvent> {
+        let mut decoder = StreamDecoder::new();
+        let mut events = Vec::new();
+        for chunk in chunks {
+            decoder.push(chunk);
+            events.extend(&mut decoder);
+        }
+        assert!(decoder.buffer.is_empty());
+        events
+    }
+
+    #[test]
+    fn test_whole_stream() {
+        let (data, expected) = stream();
+        assert_eq!(expected, decode_chunks(&[&data]));
+    }
+
+    #[test]
+    fn test_byte_by_byte() {
+        let (data, expected) = stream();
+        let chunks: Vec<&[u8]> = data.chunks(1).collect();
+        assert_eq!(expected, decode_chunks(&chunks));
+    }
+
+    #[test]
+    fn test_all_splits() {
+        let (data, expected) = stream();
+        for first in 0..=data.len() {
+            for second in first..=data.len() {
+                let chunks = [&data[..first], &data[first..second], &data[second..]];
+                assert_eq!(
+                    expected,
+                    decode_chunks(&chunks),
+                    "split at {first}, {second}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let (data, _) = stream();
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&data[..3]);
+        assert_eq!(None, decoder.next_event());
+        assert_eq!(3, decoder.buffer.len());
+    }
+
+    #[test]
+    fn test_system_info() {
+        let (_, events) = stream();
+        assert_eq!(
+            Some(SystemInfo {
+                sys_freq: 1_000_000,
+                cpu_freq: 160_000_000,
+                ram_base: 0x4080_0000,
+                chip_id: 13,
+            }),
+            events[0].system_info()
+        );
+        assert_eq!(None, events[1].system_info());
+    }
+
+    #[test]
+    fn test_garbage() {
+        let mut data = vec![0x12, SYNC, 0x02];
+        push(Event::SystemIdle { ts_delta: 10 }, &mut data);
+        data.extend_from_slice(&[SYNC, 0x03, 0x42, SYNC, 0xff]);
+        push(Event::SystemIdle { ts_delta: 20 }, &mut data);
+        push(Event::SystemIdle { ts_delta: 30 }, &mut data);
+
+        let expected = vec![
+            TargetEvent::Corrupted { frames: 1 },
+            owned(Event::SystemIdle { ts_delta: 10 }),
+            TargetEvent::Corrupted { frames: 1 },
+            owned(Event::SystemIdle { ts_delta: 20 }),
+            owned(Event::SystemIdle { ts_delta: 30 }),
+        ];
+
+        for split in 0..=data.len() {
+            let mut decoder = StreamDecoder::new();
+            let mut events = Vec::new();
+            for chunk in [&data[..split], &data[split..]] {
+                decoder.push(chunk);
+                events.extend(&mut decoder);
+            }
+            assert_eq!(expected, events, "split at {split}");
+            assert_eq!(2, decoder.corrupted());
+        }
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut data = Vec::new();
+        push(
+            Event::Marker {
+                id: 1,
+                ts_delta: 10,
+            },
+            &mut data,
+        );
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        push(
+            Event::Marker {
+                id: 2,
+                ts_delta: 20,
+            },
+            &mut data,
+        );
+
+        assert_eq!(
+            vec![
+                TargetEvent::Corrupted { frames: 1 },
+                owned(Event::Marker {
+                    id: 2,
+                    ts_delta: 20
+                }),
+            ],
+            decode_chunks(&[&data])
+        );
+    }
+
+    #[test]
+    fn test_unknown_event() {
+        let payload = [200, 1, 2, 3];
+        let mut data = vec![SYNC, payload.len() as u8];
+        data.extend_from_slice(&payload);
+        data.push(checksum(&data[1..]));
+        push(
+            Event::Marker {
+                id: 2,
+                ts_delta: 20,
+            },
+            &mut data,
+        );
+
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&data);
+        assert_eq!(
+            Some(owned(Event::Marker {
+                id: 2,
+                ts_delta: 20
+            })),
+            decoder.next_event()
+        );
+        assert_eq!(0, decoder.corrupted());
+    }
+}
@@ -0,0 +1,166 @@
+use esp_xray_protocol::MAX_NAME_LEN;
+use object::{Object, ObjectSymbol, SymbolKind};
+
+/// Symbol of the RTT control block
+const RTT_SYMBOL: &str = "_SEGGER_RTT";
+
+/// What the server needs to know from the firmware image
+#[derive(Debug, Default)]
+pub struct Elf {
+    rtt_address: Option<u64>,
+    /// Address range and demangled name of the statics, sorted by address
+    statics: Vec<(u64, u64, String)>,
+}
+
+impl Elf {
+    pub fn parse(elf: &[u8]) -> Result<Self, object::Error> {
+        let file = object::File::parse(elf)?;
+
+        let rtt_address = file
+            .symbols()
+            .find(|symbol| symbol.name() == Ok(RTT_SYMBOL))
+            .map(|symbol| symbol.address());
+
+        let mut statics: Vec<_> = file
+            .symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Data && symbol.size() > 0)
+            .filter_map(|symbol| {
+                let name = format!("{:#}", rustc_demangle::demangle(symbol.name().ok()?));
+                Some((symbol.address(), symbol.address() + symbol.size(), name))
+            })
+            .collect();
+        statics.sort();
+
+        Ok(Self {
+            rtt_address,
+            statics,
+        })
+    }
+
+    /// Address of the RTT control block, if the image has one
+    pub fn rtt_address(&self) -> Option<u64> {
+        self.rtt_address
+    }
+
+    /// Name of the embassy task with the given id
+    ///
+    /// Task ids point into the task's `POOL` static, e.g. `example::blink::POOL` - which makes
+    /// this `blink`. Ids pointing anywhere else aren't tasks. Names longer than [MAX_NAME_LEN]
+    /// bytes lose their leading modules.
+    pub fn task_name(&self, id: u32) -> Option<String> {
+        let id = id as u64;
+        let index = self.statics.partition_point(|(start, _, _)| *start <= id);
+        let (_, end, name) = self.statics[..index].last()?;
+        if id >= *end {
+            return None;
+        }
+
+        let mut path: Vec<&str> = name.split("::").collect();
+        if path.len() < 2 || path.pop() != Some("POOL") {
+            return None;
+        }
+        // drop the crate name
+        if path.len() > 1 {
+            path.remove(0);
+        }
+        if path.last() == Some(&"__embassy_main") {
+            path.pop();
+            path.push("main");
+        }
+
+        // keep the task function, the module path is less telling
+        while path.len() > 1 && path.join("::").len() > MAX_NAME_LEN {
+            path.remove(0);
+        }
+
+        let mut name = path.join("::");
+        let mut len = usize::min(name.len(), MAX_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        name.truncate(len);
+
+        Some(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use object::write::{Object, StandardSection, Symbol, SymbolSection};
+    use object::{Architecture, BinaryFormat, Endianness, SymbolFlags, SymbolScope};
+
+    use super::*;
+
+    fn elf(symbols: &[(&str, u64, u64)]) -> Vec<u8> {
+        let mut obj = Object::new(BinaryFormat::Elf, Architecture::Riscv32, Endianness::Little);
+        let section = obj.section_id(StandardSection::Data);
+        obj.append_section_data(section, &[0; 0x400], 4);
+
+        for (name, value, size) in symbols {
+            obj.add_symbol(Symbol {
+                name: name.as_bytes().to_vec(),
+                value: *value,
+                size: *size,
+                kind: SymbolKind::Data,
+                scope: SymbolScope::Linkage,
+                weak: false,
+                section: SymbolSection::Section(section),
+                flags: SymbolFlags::None,
+            });
+        }
+
+        obj.write().unwrap()
+    }
+
+    #[test]
+    fn test_rtt_address() {
+        let elf = elf(&[("FOO", 0x10, 4), (RTT_SYMBOL, 0x20, 4)]);
+        assert_eq!(Some(0x20), Elf::parse(&elf).unwrap().rtt_address());
+    }
+
+    #[test]
+    fn test_no_rtt() {
+        let elf = elf(&[("FOO", 0x10, 4)]);
+        assert_eq!(None, Elf::parse(&elf).unwrap().rtt_address());
+    }
+
+    #[test]
+    fn test_not_an_elf() {
+        assert!(Elf::parse(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_task_name() {
+        let elf = elf(&[
+            (
+                "_ZN7example14__embassy_main4POOL17h0123456789abcdefE",
+                0x100,
+                0x40,
+            ),
+            (
+                "_ZN7example3net5blink4POOL17h0123456789abcdefE",
+                0x140,
+                0x40,
+            ),
+            ("COUNTER", 0x200, 4),
+            ("_ZN7example7COUNTER17h0123456789abcdefE", 0x210, 4),
+            (
+                "_ZN7example7network8protocol9discovery25announce_service_instance4POOL17h0123456789abcdefE",
+                0x240,
+                0x40,
+            ),
+        ]);
+        let elf = Elf::parse(&elf).unwrap();
+
+        assert_eq!(Some("main".to_string()), elf.task_name(0x100));
+        assert_eq!(Some("net::blink".to_string()), elf.task_name(0x17c));
+        assert_eq!(None, elf.task_name(0x180));
+        assert_eq!(None, elf.task_name(0x202));
+        assert_eq!(None, elf.task_name(0x210));
+        assert_eq!(
+            Some("announce_service_instance".to_string()),
+            elf.task_name(0x240)
+        );
+        assert_eq!(None, elf.task_name(0x10));
+    }
+}
@@ -0,0 +1,98 @@
+//! What the export formats have in common
+
+use std::collections::HashMap;
+
+use crate::elf::Elf;
+use crate::Message;
+
+/// A trace format messages are converted to
+pub trait Exporter {
+    type Output;
+
+    /// Name a task by other means than its task info - e.g. after its static in the firmware image
+    fn name_task(&mut self, task: u32, name: &str);
+
+    fn push(&mut self, message: &Message);
+
+    /// Close what is still open and return the trace
+    fn finish(self) -> Self::Output;
+}
+
+/// Convert `messages` - tasks get named after their static in the firmware image
+pub fn convert<E: Exporter>(mut exporter: E, messages: &[Message], elf: Option<&Elf>) -> E::Output {
+    for message in messages {
+        exporter.push(message);
+
+        let (Message::TaskNew(task, _) | Message::TaskInfo(task, ..), Some(elf)) = (message, elf)
+        else {
+            continue;
+        };
+        if let Some(name) = elf.task_name(*task) {
+            exporter.name_task(*task, &name);
+        }
+    }
+
+    exporter.finish()
+}
+
+/// The time and the marker names - kept track of by every exporter
+#[derive(Default)]
+pub(crate) struct Timeline {
+    /// Time of the target in timer ticks
+    pub ticks: u64,
+    markers: HashMap<u32, String>,
+}
+
+impl Timeline {
+    pub fn push(&mut self, message: &Message) {
+        self.ticks += message.ts_delta() as u64;
+
+        if let Message::MarkerName(id, name, _) = *message {
+            self.markers.insert(id, name.to_string());
+        }
+    }
+
+    /// Markers not named by the target are named after their id
+    pub fn marker_name(&self, id: u32) -> String {
+        match self.markers.get(&id) {
+            Some(name) => name.clone(),
+            None => format!("Marker {id}"),
+        }
+    }
+}
+
+/// Shared by the tests of the exporters
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use crate::SystemInfo;
+
+    pub const INFO: SystemInfo = SystemInfo {
+        sys_freq: 1_000_000,
+        cpu_freq: 160_000_000,
+        ram_base: 0x4000_0000,
+        chip_id: 0,
+    };
+
+    pub const TASK: u32 = 0x4000_1000;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timeline() {
+        let mut timeline = Timeline::default();
+        for message in [
+            Message::MarkerName(1, "phase", 5),
+            Message::Marker(2, 10),
+            Message::SystemIdle(1),
+        ] {
+            timeline.push(&message);
+        }
+
+        assert_eq!(16, timeline.ticks);
+        assert_eq!("phase", timeline.marker_name(1));
+        assert_eq!("Marker 2", timeline.marker_name(2));
+    }
+}
@@ -1,8 +1,17 @@
+use std::cell::Cell;
 use std::io::{Read, Write};
 
-use crate::packet::{Cause, Event};
+use crate::packet::{Cause, Command, Event};
 
+pub mod capture;
+pub mod chrome;
+pub mod ctf;
+pub mod decoder;
+pub mod elf;
+pub mod export;
 pub mod packet;
+pub mod replay;
+pub mod svdat;
 
 #[macro_export]
 macro_rules! block {
@@ -22,31 +31,197 @@ macro_rules! block {
     };
 }
 
+/// Write all of `data`, waiting while a non-blocking `io` is busy
+fn write_all(io: &mut impl Write, mut data: &[u8]) -> Result<(), Error> {
+    while !data.is_empty() {
+        match block!(io.write(data)) {
+            Ok(0) | Err(_) => return Err(Error::Disconnected),
+            Ok(count) => data = &data[count..],
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Error {
     UnknownCommand,
+    Disconnected,
+    /// The host didn't send a valid HELLO
+    Handshake,
+    IncompatibleVersion(Version),
+    /// An event doesn't fit into a packet
+    BufferTooSmall,
+    /// The target reported a timestamp frequency of 0
+    InvalidSystemInfo,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum Message {
+/// SystemView protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u8,
+}
+
+impl Version {
+    /// The protocol version implemented here
+    pub const CURRENT: Version = Version::new(3, 0, 0);
+
+    /// The oldest host we can talk to
+    pub const MIN: Version = Version::new(2, 0, 0);
+
+    pub const fn new(major: u8, minor: u8, revision: u8) -> Self {
+        Self {
+            major,
+            minor,
+            revision,
+        }
+    }
+
+    /// Parse a `SEGGER SystemView Vx.yy.zz` HELLO message
+    pub fn from_hello(hello: &[u8]) -> Option<Self> {
+        let len = hello.iter().position(|b| *b == 0).unwrap_or(hello.len());
+        let text = std::str::from_utf8(&hello[..len]).ok()?;
+        let mut parts = text.strip_prefix("SEGGER SystemView V")?.split('.');
+
+        let version = Self::new(
+            parts.next()?.parse().ok()?,
+            parts.next()?.parse().ok()?,
+            parts.next()?.parse().ok()?,
+        );
+
+        match parts.next() {
+            None => Some(version),
+            Some(_) => None,
+        }
+    }
+
+    /// The version to use with a host speaking `host`
+    pub fn negotiate(host: Version) -> Result<Version, Error> {
+        if host < Self::MIN {
+            log::error!("SystemView {} is too old", host);
+            return Err(Error::IncompatibleVersion(host));
+        }
+
+        if host.major > Self::CURRENT.major {
+            log::warn!("SystemView {} is newer than {}", host, Self::CURRENT);
+        }
+
+        Ok(host.min(Self::CURRENT))
+    }
+
+    pub fn hello(&self) -> String {
+        format!("SEGGER SystemView {}", self)
+    }
+
+    /// Task termination (`SYSVIEW_EVTID_TASK_TERMINATE`, id 29) is part of the V3 protocol of
+    /// `SEGGER_SYSVIEW.h` - V2 hosts don't know the event
+    pub fn supports_task_terminate(&self) -> bool {
+        self.major >= 3
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "V{}.{:02}.{:02}", self.major, self.minor, self.revision)
+    }
+}
+
+/// Clock and memory configuration of the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemInfo {
+    /// Frequency of the timestamp ticks
+    pub sys_freq: u32,
+    pub cpu_freq: u32,
+    /// Task ids are sent relative to this address
+    pub ram_base: u32,
+    /// ESP-IDF chip model
+    pub chip_id: u32,
+}
+
+impl SystemInfo {
+    /// Timestamps can't be converted to time without a frequency
+    pub fn is_valid(&self) -> bool {
+        self.sys_freq != 0
+    }
+}
+
+impl Default for SystemInfo {
+    fn default() -> Self {
+        Self {
+            sys_freq: 16000000,
+            cpu_freq: 160000000,
+            ram_base: 0x40000000,
+            chip_id: 0,
+        }
+    }
+}
+
+/// SystemView task ids are `(id - ram_base) >> ID_SHIFT`
+const ID_SHIFT: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message<'a> {
     Disconnect(u32),
     IsrEnter(u8, u32),
     IsrExit(u32),
+    IsrToScheduler(u32),
     TaskNew(u32, u32),
     TaskExecBegin(u32, u32),
     TaskExecEnd(u32),
     TaskReadyBegin(u32, u32),
     TaskReadyEnd(u32, u32),
     SystemIdle(u32),
+    /// task, priority, name, stack base, stack size, ts_delta
+    TaskInfo(u32, u32, &'a str, u32, u32, u32),
+    TaskTerminate(u32, u32),
+    Marker(u32, u32),
+    MarkerBegin(u32, u32),
+    MarkerEnd(u32, u32),
+    MarkerName(u32, &'a str, u32),
+    SystemInfo(SystemInfo),
+    /// number of lost events, ts_delta
+    Overflow(u32, u32),
+}
+
+impl Message<'_> {
+    pub fn ts_delta(&self) -> u32 {
+        match *self {
+            Message::Disconnect(ts_delta)
+            | Message::IsrEnter(_, ts_delta)
+            | Message::IsrExit(ts_delta)
+            | Message::IsrToScheduler(ts_delta)
+            | Message::TaskNew(_, ts_delta)
+            | Message::TaskExecBegin(_, ts_delta)
+            | Message::TaskExecEnd(ts_delta)
+            | Message::TaskReadyBegin(_, ts_delta)
+            | Message::TaskReadyEnd(_, ts_delta)
+            | Message::SystemIdle(ts_delta)
+            | Message::TaskInfo(_, _, _, _, _, ts_delta)
+            | Message::TaskTerminate(_, ts_delta)
+            | Message::Marker(_, ts_delta)
+            | Message::MarkerBegin(_, ts_delta)
+            | Message::MarkerEnd(_, ts_delta)
+            | Message::MarkerName(_, _, ts_delta)
+            | Message::Overflow(_, ts_delta) => ts_delta,
+            Message::SystemInfo(_) => 0,
+        }
+    }
 }
 
 pub trait Transport<IO>
 where
     IO: Read + Write,
 {
-    fn hello(&self, io: &mut IO);
+    /// Exchange HELLO messages and agree on a protocol version
+    fn hello(&self, io: &mut IO) -> Result<Version, Error>;
 
-    fn skip_command_len(&self, io: &mut IO) -> bool;
+    /// Read the next command from the host into `cmd`
+    ///
+    /// Returns `Ok(0)` if no command is pending. A closed connection is reported as
+    /// [std::io::ErrorKind::UnexpectedEof].
+    fn read_command(&self, io: &mut IO, cmd: &mut [u8]) -> std::io::Result<usize>;
 }
 
 #[derive(Default)]
@@ -56,63 +231,154 @@ impl<IO> Transport<IO> for TcpTransport
 where
     IO: Read + Write,
 {
-    fn hello(&self, io: &mut IO) {
-        let mut buf = [0u8; 48];
-        let _count = block!(io.read(&mut buf)).unwrap();
-
-        // TODO we should check the host's HELLO
-
-        io.write_all(&[
-            b'S',
-            b'E',
-            b'G',
-            b'G',
-            b'E',
-            b'R',
-            b' ',
-            b'S',
-            b'y',
-            b's',
-            b't',
-            b'e',
-            b'm',
-            b'V',
-            b'i',
-            b'e',
-            b'w',
-            b' ',
-            b'V',
-            b'0' + 3,
-            b'.',
-            b'0' + (0 / 10),
-            b'0' + (0 % 10),
-            b'.',
-            b'0' + (0 / 10),
-            b'0' + (0 % 10),
-            b'\0',
-            0,
-            0,
-            0,
-            0,
-            0,
-        ])
-        .unwrap();
+    fn hello(&self, io: &mut IO) -> Result<Version, Error> {
+        const PREFIX: &[u8] = b"SEGGER SystemView V";
+
+        // the HELLO can arrive in several segments
+        let mut buf = [0u8; 32];
+        let mut count = 0;
+        while count < buf.len() {
+            match block!(io.read(&mut buf[count..])) {
+                Ok(0) | Err(_) => return Err(Error::Disconnected),
+                Ok(len) => count += len,
+            }
+
+            // don't wait for the rest of something which isn't a HELLO
+            let len = usize::min(count, PREFIX.len());
+            if buf[..len] != PREFIX[..len] {
+                return Err(Error::Handshake);
+            }
+        }
+
+        let host = Version::from_hello(&buf).ok_or(Error::Handshake)?;
+        let version = Version::negotiate(host)?;
+
+        let mut hello = [0u8; 32];
+        let text = version.hello();
+        hello[..text.len()].copy_from_slice(text.as_bytes());
+        write_all(io, &hello)?;
 
         // AB sync
-        io.write_all(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
-            .unwrap();
+        write_all(
+            io,
+            &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        )?;
+
+        Ok(version)
+    }
+
+    fn read_command(&self, io: &mut IO, cmd: &mut [u8]) -> std::io::Result<usize> {
+        let mut len = [0u8];
+        match io.read(&mut len) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(0),
+            Err(e) => return Err(e),
+        }
+
+        // read no further than this command - the next one can be in the same segment
+        let cmd = cmd
+            .get_mut(..len[0] as usize)
+            .ok_or(std::io::ErrorKind::InvalidData)?;
+        let mut count = 0;
+        while count < cmd.len() {
+            match block!(io.read(&mut cmd[count..]))? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                len => count += len,
+            }
+        }
+
+        Ok(count)
     }
+}
+
+/// SystemView's UART recorder
+///
+/// The HELLO is `SV` followed by the major and minor version. Commands are sent as is, without a
+/// length - like SEGGER's UART sample, which passes each received byte on to the RTT down channel.
+/// Length-prefixed command framing isn't supported.
+#[derive(Default)]
+pub struct UartTransport {}
 
-    fn skip_command_len(&self, io: &mut IO) -> bool {
-        let mut buf = [0u8];
-        match io.read_exact(&mut buf) {
-            Ok(_) => true,
-            Err(_) => false,
+impl UartTransport {
+    fn parse_hello(hello: &[u8]) -> Option<Version> {
+        match hello {
+            [b'S', b'V', major, minor] => Some(Version::new(*major, minor.checked_mul(10)?, 0)),
+            _ => None,
         }
     }
 }
 
-// TODO UART
+impl<IO> Transport<IO> for UartTransport
+where
+    IO: Read + Write,
+{
+    fn hello(&self, io: &mut IO) -> Result<Version, Error> {
+        let mut buf = [0u8; 4];
+        let mut count = 0;
+        while count < buf.len() {
+            match block!(io.read(&mut buf[count..])) {
+                Ok(0) | Err(_) => return Err(Error::Disconnected),
+                Ok(len) => count += len,
+            }
+        }
+
+        let host = Self::parse_hello(&buf).ok_or(Error::Handshake)?;
+        let version = Version::negotiate(host)?;
+
+        write_all(io, &[b'S', b'V', version.major, version.minor / 10])?;
+
+        Ok(version)
+    }
+
+    fn read_command(&self, io: &mut IO, cmd: &mut [u8]) -> std::io::Result<usize> {
+        match io.read(&mut cmd[..1]) {
+            Ok(0) => Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(count) => Ok(count),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Records to a SystemView data file (.SVDat) instead of talking to SystemView
+///
+/// The file starts with a text header, followed by the stream SystemView would receive. Recording
+/// starts right away - there are no commands.
+#[derive(Default)]
+pub struct SvDatTransport {
+    started: Cell<bool>,
+}
+
+impl<IO> Transport<IO> for SvDatTransport
+where
+    IO: Read + Write,
+{
+    fn hello(&self, io: &mut IO) -> Result<Version, Error> {
+        let version = Version::CURRENT;
+
+        write!(
+            io,
+            ";\n; Version     SEGGER SystemViewer {version}\n; Author      esp-xray\n;\n"
+        )
+        .map_err(|_| Error::Disconnected)?;
+
+        // sync
+        io.write_all(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .map_err(|_| Error::Disconnected)?;
+
+        Ok(version)
+    }
+
+    fn read_command(&self, _io: &mut IO, cmd: &mut [u8]) -> std::io::Result<usize> {
+        if self.started.replace(true) {
+            return Ok(0);
+        }
+
+        cmd[0] = Command::Start as u8;
+        Ok(1)
+    }
+}
 
 pub struct SystemViewTarget<T, IO>
 where
@@ -121,6 +387,10 @@ where
 {
     transport: T,
     io: IO,
+    info: SystemInfo,
+    systime: u64,
+    started: bool,
+    version: Version,
 }
 
 impl<T, IO> SystemViewTarget<T, IO>
@@ -128,126 +398,561 @@ where
     T: Transport<IO>,
     IO: Read + Write,
 {
-    pub fn new(transport: T, mut io: IO) -> Self {
-        transport.hello(&mut io);
+    /// `systime` is the current time of the target in timer ticks
+    ///
+    /// Returns once the host started recording.
+    pub fn new(transport: T, mut io: IO, info: SystemInfo, systime: u64) -> Result<Self, Error> {
+        log::info!("Target {:x?}", info);
+        if !info.is_valid() {
+            return Err(Error::InvalidSystemInfo);
+        }
 
-        let mut cmd = [0u8; 5];
-        let mut out = [0u8; 32];
+        let version = transport.hello(&mut io)?;
+        log::info!("Using protocol {}", version);
 
-        // read start command
-        while !transport.skip_command_len(&mut io) {}
-        let _count = block!(io.read(&mut cmd)).unwrap();
+        let mut this = Self {
+            transport,
+            io,
+            info,
+            systime,
+            started: false,
+            version,
+        };
 
-        // should be answer to Command::Start
-        let l = Event::TraceStart { ts_delta: 0 }.encode(&mut out).unwrap();
-        io.write(&out[..l]).unwrap();
+        while !this.started {
+            match this.process_incoming() {
+                Ok(()) | Err(Error::UnknownCommand) => (),
+                Err(err) => return Err(err),
+            }
+        }
 
-        // TODO get it from the target
-        let l = Event::Init {
-            sys_freq: 16000000,
-            cpu_freq: 160000000,
-            ram_base: 0x40000000,
-            id_shift: 2,
-            ts_delta: 1,
+        Ok(this)
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn system_info(&self) -> SystemInfo {
+        self.info
+    }
+
+    /// Current time of the target in timer ticks
+    pub fn systime(&self) -> u64 {
+        self.systime
+    }
+
+    /// Flush what was sent so far
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.io.flush()
+    }
+
+    fn task_id(&self, task: u32) -> u32 {
+        task.wrapping_sub(self.info.ram_base) >> ID_SHIFT
+    }
+
+    /// Handle a pending command from the host
+    ///
+    /// Returns [Error::Disconnected] once the host closed the connection.
+    pub fn process_incoming(&mut self) -> Result<(), Error> {
+        let mut cmd = [0u8; 5];
+        let count = self
+            .transport
+            .read_command(&mut self.io, &mut cmd)
+            .map_err(|_| Error::Disconnected)?;
+
+        if count == 0 {
+            return Ok(());
         }
-        .encode(&mut out)
-        .unwrap();
-        io.write(&out[..l]).unwrap();
 
-        let l = Event::SystimeCycles {
-            time: 1000,
-            ts_delta: 3,
+        let command = Command::try_from(cmd[0]).inspect_err(|_| {
+            log::warn!("Unknown command {:02x?}", &cmd[..count]);
+        })?;
+        log::info!("Command {:?}", command);
+
+        match command {
+            Command::Start => self.start(),
+            Command::Stop => self.stop(),
+            Command::GetSysTime => self.send_systime(),
         }
-        .encode(&mut out)
-        .unwrap();
-        io.write_all(&out[..l]).unwrap();
+    }
 
-        let l = Event::NumModules {
+    fn start(&mut self) -> Result<(), Error> {
+        self.write_event(Event::TraceStart { ts_delta: 0 })?;
+        self.write_event(Event::Init {
+            sys_freq: self.info.sys_freq,
+            cpu_freq: self.info.cpu_freq,
+            ram_base: self.info.ram_base,
+            id_shift: ID_SHIFT,
+            ts_delta: 1,
+        })?;
+        self.write_event(Event::SystimeCycles {
+            time: self.systime as u32,
+            ts_delta: 3,
+        })?;
+        self.write_event(Event::NumModules {
             modules: 0,
             ts_delta: 4,
-        }
-        .encode(&mut out)
-        .unwrap();
-        io.write_all(&out[..l]).unwrap();
+        })?;
 
-        Self { transport, io }
+        self.started = true;
+        Ok(())
     }
 
-    pub fn process_incoming(&mut self) -> bool {
-        let mut cmd = [0u8; 5];
-        let transport = &mut self.transport;
-        let io = &mut self.io;
-
-        if transport.skip_command_len(io) {
-            let _count = block!(io.read(&mut cmd)).unwrap();
-            // for now assume anything from the host will be a Disconnect command
-            // TODO send Event::TraceStop
-            let mut out = [0u8; 32];
-            let l = Event::TraceStop { ts_delta: 0 }.encode(&mut out).unwrap();
-            io.write_all(&out[..l]).unwrap();
+    fn stop(&mut self) -> Result<(), Error> {
+        self.write_event(Event::TraceStop { ts_delta: 0 })?;
 
-            return _count > 0;
-        }
+        self.started = false;
+        Ok(())
+    }
 
-        false
+    fn send_systime(&mut self) -> Result<(), Error> {
+        self.write_event(Event::SystimeCycles {
+            time: self.systime as u32,
+            ts_delta: 0,
+        })?;
+        self.write_event(Event::SystimeUs {
+            time: self.systime * 1_000_000 / self.info.sys_freq as u64,
+            ts_delta: 0,
+        })
     }
 
-    pub fn send(&mut self, msg: Message) {
+    /// Forward a message from the target
+    ///
+    /// Returns [Error::Disconnected] once the host can't be written to anymore.
+    pub fn send(&mut self, msg: Message) -> Result<(), Error> {
         log::info!("Run...");
 
-        let mut out = [0u8; 32];
-        match msg {
-            Message::IsrEnter(isr, ts_delta) => {
-                let l = Event::IsrEnter { isr, ts_delta }.encode(&mut out).unwrap();
-                self.io.write_all(&out[..l]).unwrap();
-            }
-            Message::IsrExit(ts_delta) => {
-                let l = Event::IsrExit { ts_delta }.encode(&mut out).unwrap();
-                self.io.write_all(&out[..l]).unwrap();
+        self.systime += msg.ts_delta() as u64;
+
+        if !self.started {
+            return Ok(());
+        }
+
+        let result = match msg {
+            Message::IsrEnter(isr, ts_delta) => self.write_event(Event::IsrEnter { isr, ts_delta }),
+            Message::IsrExit(ts_delta) => self.write_event(Event::IsrExit { ts_delta }),
+            Message::IsrToScheduler(ts_delta) => {
+                self.write_event(Event::IsrToScheduler { ts_delta })
             }
             Message::Disconnect(ts_delta) => {
                 // HOST disconnect
-                let l = Event::TraceStop { ts_delta }.encode(&mut out).unwrap();
-                self.io.write_all(&out[..l]).unwrap();
+                self.write_event(Event::TraceStop { ts_delta })
             }
             Message::TaskNew(task, ts_delta) => {
-                let l = Event::TaskCreate { task, ts_delta }
-                    .encode(&mut out)
-                    .unwrap();
-                self.io.write_all(&out[..l]).unwrap();
+                let task = self.task_id(task);
+                self.write_event(Event::TaskCreate { task, ts_delta })
             }
             Message::TaskExecBegin(task, ts_delta) => {
-                let l = Event::TaskStartExec { task, ts_delta }
-                    .encode(&mut out)
-                    .unwrap();
-                self.io.write_all(&out[..l]).unwrap();
-            }
-            Message::TaskExecEnd(ts_delta) => {
-                let l = Event::TaskStopExec { ts_delta }.encode(&mut out).unwrap();
-                self.io.write_all(&out[..l]).unwrap();
+                let task = self.task_id(task);
+                self.write_event(Event::TaskStartExec { task, ts_delta })
             }
+            Message::TaskExecEnd(ts_delta) => self.write_event(Event::TaskStopExec { ts_delta }),
             Message::TaskReadyBegin(task, ts_delta) => {
-                let l = Event::TaskStartReady { task, ts_delta }
-                    .encode(&mut out)
-                    .unwrap();
-                self.io.write_all(&out[..l]).unwrap();
+                let task = self.task_id(task);
+                self.write_event(Event::TaskStartReady { task, ts_delta })
             }
             Message::TaskReadyEnd(task, ts_delta) => {
-                let l = Event::TaskStopReady {
+                let task = self.task_id(task);
+                self.write_event(Event::TaskStopReady {
                     task,
                     cause: Cause::Idle,
                     ts_delta,
+                })
+            }
+            Message::SystemIdle(ts_delta) => self.write_event(Event::Idle { ts_delta }),
+            Message::TaskInfo(task, prio, name, stack_base, stack_size, ts_delta) => {
+                let task = self.task_id(task);
+                self.write_event(Event::TaskInfo {
+                    task,
+                    prio,
+                    name,
+                    ts_delta,
+                })?;
+                self.write_event(Event::StackInfo {
+                    task_id: task,
+                    stack_base,
+                    stack_size,
+                    ts_delta: 0,
+                })
+            }
+            Message::TaskTerminate(task_id, ts_delta) => {
+                if !self.version.supports_task_terminate() {
+                    // the task at least stops running
+                    self.write_event(Event::TaskStopExec { ts_delta })
+                } else {
+                    let task_id = self.task_id(task_id);
+                    self.write_event(Event::TaskTerminate { task_id, ts_delta })
                 }
-                .encode(&mut out)
-                .unwrap();
-                self.io.write_all(&out[..l]).unwrap();
             }
-            Message::SystemIdle(ts_delta) => {
-                let l = Event::Idle { ts_delta }.encode(&mut out).unwrap();
-                self.io.write_all(&out[..l]).unwrap();
+            Message::Marker(user_id, ts_delta) => {
+                // SystemView has no single point user events - use a zero-length span
+                self.write_event(Event::UserStart { user_id, ts_delta })?;
+                self.write_event(Event::UserStop {
+                    user_id,
+                    ts_delta: 0,
+                })
             }
-        }
+            Message::MarkerBegin(user_id, ts_delta) => {
+                self.write_event(Event::UserStart { user_id, ts_delta })
+            }
+            Message::MarkerEnd(user_id, ts_delta) => {
+                self.write_event(Event::UserStop { user_id, ts_delta })
+            }
+            Message::MarkerName(resource_id, name, ts_delta) => {
+                // user events can't be named - the name only shows up as the resource with the id
+                self.write_event(Event::NameResource {
+                    resource_id,
+                    name,
+                    ts_delta,
+                })
+            }
+            Message::Overflow(dropped_packets, ts_delta) => self.write_event(Event::Overflow {
+                dropped_packets,
+                ts_delta,
+            }),
+            Message::SystemInfo(info) if !info.is_valid() => {
+                log::warn!("Ignoring {:x?}", info);
+                Ok(())
+            }
+            Message::SystemInfo(info) => {
+                // SystemView can't handle another Init packet - only used for the next connection
+                self.info = info;
+                Ok(())
+            }
+        };
 
         log::info!("Done.");
+        result
+    }
+
+    /// Events which can't be encoded are dropped
+    fn write_event(&mut self, event: Event) -> Result<(), Error> {
+        let mut out = [0u8; 64];
+        match event.encode(&mut out) {
+            Ok(l) => write_all(&mut self.io, &out[..l]),
+            Err(err) => {
+                log::warn!("Dropping {:?}: {:?}", event, err);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// In-memory connection
+    ///
+    /// Every read returns at most one of the packets sent by the host. Reading blocks when there
+    /// is nothing to read until the connection is closed. Writing to a closed connection fails.
+    #[derive(Default)]
+    struct MockIo {
+        input: VecDeque<Vec<u8>>,
+        output: Vec<u8>,
+        closed: bool,
+    }
+
+    impl MockIo {
+        fn host(packets: &[&[u8]]) -> Self {
+            Self {
+                input: packets.iter().map(|p| p.to_vec()).collect(),
+                ..Default::default()
+            }
+        }
+
+        fn send(&mut self, packet: &[u8]) {
+            self.input.push_back(packet.to_vec());
+        }
+    }
+
+    impl Read for MockIo {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(packet) = self.input.front_mut() else {
+                if self.closed {
+                    return Ok(0);
+                }
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            };
+
+            let count = usize::min(buf.len(), packet.len());
+            buf[..count].copy_from_slice(&packet[..count]);
+            packet.drain(..count);
+            if packet.is_empty() {
+                self.input.pop_front();
+            }
+
+            Ok(count)
+        }
+    }
+
+    impl Write for MockIo {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.closed {
+                return Err(std::io::ErrorKind::ConnectionReset.into());
+            }
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const HOST_HELLO: &[u8; 32] = b"SEGGER SystemView V3.54.00\0\0\0\0\0\0";
+
+    fn started(systime: u64) -> SystemViewTarget<TcpTransport, MockIo> {
+        let io = MockIo::host(&[HOST_HELLO, &[1], &[Command::Start as u8]]);
+
+        let mut xray =
+            SystemViewTarget::new(TcpTransport::default(), io, SystemInfo::default(), systime)
+                .unwrap();
+        xray.io.output.clear();
+        xray
+    }
+
+    #[test]
+    fn test_parse_hello() {
+        assert_eq!(
+            Some(Version::new(3, 54, 0)),
+            Version::from_hello(HOST_HELLO)
+        );
+        assert_eq!(
+            Some(Version::new(2, 52, 3)),
+            Version::from_hello(b"SEGGER SystemView V2.52.03")
+        );
+        assert_eq!(None, Version::from_hello(b"SEGGER SystemView V3.54"));
+        assert_eq!(None, Version::from_hello(b"SEGGER SystemView 3.54.00\0"));
+        assert_eq!(None, Version::from_hello(b"GET / HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_hello_current() {
+        let mut io = MockIo::host(&[HOST_HELLO]);
+        let version = TcpTransport::default().hello(&mut io).unwrap();
+
+        assert_eq!(Version::CURRENT, version);
+        assert_eq!(b"SEGGER SystemView V3.00.00\0", &io.output[..27]);
+        assert_eq!(32 + 10, io.output.len());
+    }
+
+    #[test]
+    fn test_hello_older_host() {
+        let mut io = MockIo::host(&[b"SEGGER SystemView V2.52.00\0\0\0\0\0\0"]);
+        let version = TcpTransport::default().hello(&mut io).unwrap();
+
+        assert_eq!(Version::new(2, 52, 0), version);
+        assert_eq!(b"SEGGER SystemView V2.52.00\0", &io.output[..27]);
+    }
+
+    #[test]
+    fn test_hello_incompatible() {
+        let io = MockIo::host(&[b"SEGGER SystemView V1.00.00\0\0\0\0\0\0"]);
+        let result = SystemViewTarget::new(TcpTransport::default(), io, SystemInfo::default(), 0);
+
+        assert!(matches!(
+            result,
+            Err(Error::IncompatibleVersion(Version {
+                major: 1,
+                minor: 0,
+                revision: 0
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_hello_garbage() {
+        let mut io = MockIo::host(&[b"GET / HTTP/1.1\r\n"]);
+        let result = TcpTransport::default().hello(&mut io);
+
+        assert!(matches!(result, Err(Error::Handshake)));
+        assert!(io.output.is_empty());
+    }
+
+    #[test]
+    fn test_uart() {
+        let io = MockIo::host(&[b"SV", &[3, 5], &[Command::Start as u8]]);
+        let mut xray =
+            SystemViewTarget::new(UartTransport::default(), io, SystemInfo::default(), 0).unwrap();
+
+        assert_eq!(Version::CURRENT, xray.version());
+        assert_eq!(b"SV\x03\x00", &xray.io.output[..4]);
+        assert_eq!(0x0a, xray.io.output[4]);
+
+        xray.io.output.clear();
+        xray.io.send(&[Command::Stop as u8]);
+        assert!(xray.process_incoming().is_ok());
+        assert_eq!(&[0x0b, 0x00], &xray.io.output[..]);
+    }
+
+    #[test]
+    fn test_uart_invalid_hello() {
+        let mut io = MockIo::host(&[b"SV", &[3, 26]]);
+        let result = UartTransport::default().hello(&mut io);
+
+        assert!(matches!(result, Err(Error::Handshake)));
+        assert!(io.output.is_empty());
+    }
+
+    #[test]
+    fn test_svdat() {
+        let mut xray = SystemViewTarget::new(
+            SvDatTransport::default(),
+            MockIo::default(),
+            SystemInfo::default(),
+            0,
+        )
+        .unwrap();
+
+        let header = b";\n; Version     SEGGER SystemViewer V3.00.00\n; Author      esp-xray\n;\n";
+        assert_eq!(header, &xray.io.output[..header.len()]);
+        assert_eq!(&[0u8; 10], &xray.io.output[header.len()..][..10]);
+        assert_eq!(0x0a, xray.io.output[header.len() + 10]);
+
+        xray.io.output.clear();
+        assert!(xray.process_incoming().is_ok());
+        assert!(xray.io.output.is_empty());
+
+        xray.send(Message::SystemIdle(5)).unwrap();
+        assert_eq!(&[0x11, 0x05], &xray.io.output[..]);
+    }
+
+    #[test]
+    fn test_hello_split() {
+        let mut io = MockIo::host(&[&HOST_HELLO[..10], &HOST_HELLO[10..20], &HOST_HELLO[20..]]);
+        let version = TcpTransport::default().hello(&mut io).unwrap();
+
+        assert_eq!(Version::CURRENT, version);
+        assert_eq!(32 + 10, io.output.len());
+    }
+
+    #[test]
+    fn test_no_task_terminate_for_v2() {
+        let io = MockIo::host(&[
+            b"SEGGER SystemView V2.52.00\0\0\0\0\0\0",
+            &[1],
+            &[Command::Start as u8],
+        ]);
+        let mut xray =
+            SystemViewTarget::new(TcpTransport::default(), io, SystemInfo::default(), 0).unwrap();
+        xray.io.output.clear();
+
+        xray.send(Message::TaskTerminate(0x4000_0010, 1)).unwrap();
+        // sent as the task stopping to run
+        assert_eq!(&[0x05, 0x01], &xray.io.output[..]);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut xray = started(0);
+        xray.send(Message::Overflow(3, 0x50)).unwrap();
+
+        assert_eq!(&[0x01, 0x03, 0x50], &xray.io.output[..]);
+        assert_eq!(0x50, xray.systime());
+    }
+
+    #[test]
+    fn test_name_too_long() {
+        let mut xray = started(0);
+        let name = "x".repeat(100);
+        xray.send(Message::MarkerName(1, &name, 5)).unwrap();
+
+        assert!(xray.io.output.is_empty());
+        assert_eq!(5, xray.systime());
+    }
+
+    #[test]
+    fn test_zero_sys_freq() {
+        let info = SystemInfo {
+            sys_freq: 0,
+            ..SystemInfo::default()
+        };
+
+        let io = MockIo::host(&[HOST_HELLO]);
+        let result = SystemViewTarget::new(TcpTransport::default(), io, info, 0);
+        assert!(matches!(result, Err(Error::InvalidSystemInfo)));
+
+        let mut xray = started(0);
+        xray.send(Message::SystemInfo(info)).unwrap();
+        assert_eq!(SystemInfo::default(), xray.system_info());
+    }
+
+    #[test]
+    fn test_get_systime() {
+        let mut xray = started(16_000_000);
+        xray.io.send(&[1]);
+        xray.io.send(&[Command::GetSysTime as u8]);
+
+        assert!(xray.process_incoming().is_ok());
+        assert_eq!(
+            &[
+                0x0c, 0x80, 0xc8, 0xd0, 0x07, 0x00, // SystimeCycles 16_000_000
+                0x0d, 0xc0, 0x84, 0x3d, 0x00, 0x00, // SystimeUs 1_000_000
+            ],
+            &xray.io.output[..]
+        );
+    }
+
+    #[test]
+    fn test_stop_start() {
+        let mut xray = started(0);
+        xray.io.send(&[1]);
+        xray.io.send(&[Command::Stop as u8]);
+        assert!(xray.process_incoming().is_ok());
+        assert_eq!(&[0x0b, 0x00], &xray.io.output[..]);
+
+        xray.io.output.clear();
+        xray.send(Message::SystemIdle(5)).unwrap();
+        assert!(xray.io.output.is_empty());
+
+        xray.io.send(&[1]);
+        xray.io.send(&[Command::Start as u8]);
+        assert!(xray.process_incoming().is_ok());
+        assert_eq!(0x0a, xray.io.output[0]);
+        assert_eq!(5, xray.systime());
+    }
+
+    #[test]
+    fn test_commands_in_one_packet() {
+        let mut xray = started(0);
+        xray.io
+            .send(&[1, Command::Stop as u8, 1, Command::Start as u8]);
+
+        assert!(xray.process_incoming().is_ok());
+        assert_eq!(&[0x0b, 0x00], &xray.io.output[..]);
+        assert!(xray.process_incoming().is_ok());
+        assert_eq!(0x0a, xray.io.output[2]);
+    }
+
+    #[test]
+    fn test_send_disconnected() {
+        let mut xray = started(0);
+        xray.io.closed = true;
+        assert!(matches!(
+            xray.send(Message::SystemIdle(5)),
+            Err(Error::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let mut xray = started(0);
+        xray.io.send(&[1]);
+        xray.io.send(&[0x55]);
+        assert!(matches!(
+            xray.process_incoming(),
+            Err(Error::UnknownCommand)
+        ));
+        assert!(xray.process_incoming().is_ok());
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut xray = started(0);
+        xray.io.closed = true;
+        assert!(matches!(xray.process_incoming(), Err(Error::Disconnected)));
     }
 }
@@ -1,181 +1,1049 @@
-use std::net::TcpListener;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use esp_xray_server::Message;
-use probe_rs::config::{MemoryRegion, TargetSelector};
-use probe_rs::rtt::{Rtt, ScanRegion};
-use probe_rs::{probe::list::Lister, Permissions};
+use anyhow::{bail, Context};
+use esp_xray_server::capture::{CaptureWriter, Metadata};
+use esp_xray_server::chrome::ChromeTrace;
+use esp_xray_server::ctf::CtfTrace;
+use esp_xray_server::decoder::{StreamDecoder, TargetEvent};
+use esp_xray_server::elf::Elf;
+use esp_xray_server::export;
+use esp_xray_server::replay::{replay, Recording};
+use esp_xray_server::{
+    Error, Message, SvDatTransport, SystemInfo, SystemViewTarget, TcpTransport, Transport,
+    UartTransport,
+};
+use probe_rs::config::TargetSelector;
+use probe_rs::flashing::{download_file, Format};
+use probe_rs::probe::{list::Lister, DebugProbeSelector, Probe};
+use probe_rs::rtt::{Rtt, ScanRegion, UpChannel};
+use probe_rs::{Core, Permissions, Session};
+use serialport::SerialPort;
+use sha2::{Digest, Sha256};
 
-use clap::Parser;
-
-enum TargetEvent {
-    TaskNew = 1,
-    TaskExecBegin,
-    TaskExecEnd,
-    TaskReadyBegin,
-    TaskReadyEnd,
-    SystemIdle,
-}
-
-impl TargetEvent {
-    pub fn from(value: u8) -> Self {
-        match value {
-            1 => Self::TaskNew,
-            2 => Self::TaskExecBegin,
-            3 => Self::TaskExecEnd,
-            4 => Self::TaskReadyBegin,
-            5 => Self::TaskReadyEnd,
-            6 => Self::SystemIdle,
-            _ => {
-                panic!("Unknown Event {value}");
-            }
-        }
-    }
-}
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
-    chip: String,
+    chip: Option<String>,
+
+    /// Debug probe to use - `VID:PID` or `VID:PID:SERIAL`, see `--list-probes`
+    #[arg(long)]
+    probe: Option<DebugProbeSelector>,
+
+    /// List the connected debug probes and exit
+    #[arg(long)]
+    list_probes: bool,
+
+    /// Protocol speed in kHz
+    #[arg(long)]
+    speed: Option<u32>,
+
+    /// Hold the target in reset while attaching
+    #[arg(long)]
+    connect_under_reset: bool,
+
+    /// Core to trace
+    #[arg(long, default_value_t = 0)]
+    core: usize,
+
+    /// Serve SystemView's UART recorder on a serial port instead of TCP
+    #[arg(long)]
+    serial: Option<String>,
+
+    /// Baud rate of the serial port
+    #[arg(long, default_value_t = 115200)]
+    baud: u32,
+
+    /// Name of the RTT up channel the target traces to
+    #[arg(long, default_value = "Xray")]
+    channel: String,
+
+    /// Print what the target writes to the other RTT up channels
+    #[arg(long)]
+    forward_logs: bool,
+
+    /// Firmware image - used to find the RTT control block without scanning RAM and to name
+    /// embassy tasks
+    #[arg(long)]
+    elf: Option<PathBuf>,
+
+    /// Also record the trace to a SystemView data file (.SVDat)
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Dump what the target sent to a file - can be replayed and decoded again later
+    #[arg(long)]
+    capture: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Flash the firmware and trace it from reset on
+    Run {
+        /// Firmware image
+        elf: PathBuf,
+
+        /// Start the firmware right away instead of waiting for SystemView to connect
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Record the trace to a SystemView data file (.SVDat) without SystemView connected
+    Record {
+        /// File to record to
+        file: PathBuf,
+
+        /// Stop recording after this many seconds instead of running until interrupted
+        #[arg(long)]
+        duration: Option<u64>,
+    },
+    /// Serve a recorded trace to SystemView - no target needed
+    Replay {
+        /// SystemView data file or what the target sent
+        file: PathBuf,
+
+        /// Replay this many times faster than it was recorded
+        #[arg(long, default_value_t = 1.0, value_parser = parse_speedup)]
+        speedup: f64,
+    },
+    /// Convert a recorded trace for other tools - no target needed
+    Export {
+        /// SystemView data file, capture or what the target sent
+        file: PathBuf,
+
+        /// File to write - a directory for CTF
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = ExportFormat::Chrome)]
+        format: ExportFormat,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ExportFormat {
+    /// Chrome trace-event JSON - opens in ui.perfetto.dev
+    Chrome,
+    /// CTF 1.8 - `--output` is a directory, opens in Trace Compass and babeltrace
+    Ctf,
+}
+
+fn parse_speedup(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(speedup) if speedup.is_finite() && speedup > 0.0 => Ok(speedup),
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
 }
 
 fn normalize(chip_name: &str) -> String {
     chip_name.replace('-', "").to_ascii_lowercase()
 }
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    let chip = normalize(&args.chip);
 
     let lister = Lister::new();
 
-    let probes = lister.list_all();
+    if args.list_probes {
+        list_probes(&lister);
+        return Ok(());
+    }
 
-    if probes.is_empty() {
-        panic!("No debug probes available. Make sure your probe is plugged in, supported and up-to-date.");
+    if let Some(Command::Replay { file, speedup }) = &args.command {
+        let host = Host::open(args.serial.as_deref(), args.baud)?;
+        return replay_file(file, *speedup, host);
     }
 
-    let probe = probes[0].open().unwrap();
+    if let Some(Command::Export {
+        file,
+        output,
+        format,
+    }) = &args.command
+    {
+        let elf = args.elf.as_deref().map(load_elf).transpose()?;
+        return export(file, output, *format, elf.as_ref());
+    }
 
-    let target_selector = TargetSelector::from(chip);
+    let Some(chip) = args.chip.as_deref() else {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--chip is required to attach to the target",
+            )
+            .exit();
+    };
+    let chip = normalize(chip);
 
-    let mut session = match probe.attach(target_selector, Permissions::default()) {
-        Ok(session) => session,
-        Err(err) => {
-            panic!("attach failed {:?}", err);
+    let mut probe = open_probe(&lister, args.probe.as_ref())?;
+
+    if let Some(speed) = args.speed {
+        probe
+            .set_speed(speed)
+            .with_context(|| format!("Error setting the speed to {speed} kHz"))?;
+    }
+
+    let probe_name = match &args.probe {
+        Some(selector) => format!("{} ({selector})", probe.get_name()),
+        None => probe.get_name(),
+    };
+
+    let target_selector = TargetSelector::from(chip.as_str());
+
+    let mut session = if args.connect_under_reset {
+        probe.attach_under_reset(target_selector, Permissions::default())
+    } else {
+        probe.attach(target_selector, Permissions::default())
+    }
+    .context("Error attaching to the target")?;
+
+    let (elf_path, wait) = match &args.command {
+        Some(Command::Run { elf, no_wait }) => {
+            flash(&mut session, elf)?;
+            (Some(elf.as_path()), !no_wait)
         }
+        _ => (args.elf.as_deref(), false),
+    };
+    let reset = matches!(args.command, Some(Command::Run { .. }));
+    let elf = elf_path.map(load_elf).transpose()?;
+
+    let mut core = session
+        .core(args.core)
+        .with_context(|| format!("Error attaching to core #{}", args.core))?;
+
+    if reset {
+        core.reset_and_halt(Duration::from_millis(500))
+            .context("Error resetting the target")?;
+    }
+
+    let mut host = match &args.command {
+        Some(Command::Record { .. }) => None,
+        _ => Some(Host::open(args.serial.as_deref(), args.baud)?),
     };
 
-    let memory_map: Vec<MemoryRegion> = vec![session
-        .target()
-        .memory_map
-        .clone()
+    if let (true, Some(host)) = (wait, &mut host) {
+        println!("Waiting for SystemView to connect ...");
+        host.wait()?;
+    }
+
+    if core.core_halted()? {
+        core.run().context("Error starting the target")?;
+    }
+
+    let scan_region = match elf.as_ref().and_then(Elf::rtt_address) {
+        Some(address) => ScanRegion::Exact(address),
+        None => ScanRegion::Ram,
+    };
+
+    if let (Some(path), ScanRegion::Ram) = (elf_path, &scan_region) {
+        eprintln!("No RTT control block in {} - scanning RAM", path.display());
+    }
+
+    eprintln!("Attaching to RTT... {:x?}", &scan_region);
+
+    let mut rtt = attach_rtt(&mut core, &scan_region, reset)?;
+
+    let names: Vec<&str> = rtt
+        .up_channels()
         .iter()
-        .filter(|m| matches!(m, probe_rs::config::MemoryRegion::Ram(_)))
-        .next()
-        .unwrap()
-        .clone()];
+        .map(|channel| channel.name().unwrap_or("<unnamed>"))
+        .collect();
+    let names = names.join(", ");
 
-    let mut core = match session.core(0) {
-        Ok(core) => core,
-        Err(err) => {
-            panic!("Error attaching to core # 0 {err}");
+    let (mut trace_channel, logs): (Vec<_>, Vec<_>) = rtt
+        .up_channels()
+        .iter_mut()
+        .partition(|channel| channel.name() == Some(args.channel.as_str()));
+
+    let mut channels = match trace_channel.pop() {
+        Some(trace) => Channels {
+            trace,
+            logs: if args.forward_logs { logs } else { Vec::new() },
+        },
+        None => bail!(
+            "No RTT up channel named {} - the target has: {names}",
+            args.channel
+        ),
+    };
+
+    let (record, duration) = match &args.command {
+        Some(Command::Record { file, duration }) => (Some(file.clone()), *duration),
+        _ => (args.record.clone(), None),
+    };
+
+    let capture = match &args.capture {
+        Some(path) => {
+            let metadata = Metadata {
+                chip,
+                probe: probe_name,
+                elf_sha256: elf_path.map(sha256).transpose()?,
+                server_version: env!("CARGO_PKG_VERSION").to_string(),
+            };
+            let file =
+                File::create(path).with_context(|| format!("Error creating {}", path.display()))?;
+            let capture = CaptureWriter::new(BufWriter::new(file), &metadata)
+                .with_context(|| format!("Error writing {}", path.display()))?;
+            Some(capture)
         }
+        None => None,
     };
 
-    eprintln!("Attaching to RTT... {:x?}", &memory_map);
+    let mut trace = Trace::new(elf, record, capture);
+
+    match host {
+        None => {
+            println!("Attached ... recording");
+
+            let start = Instant::now();
+            while duration.is_none_or(|secs| start.elapsed() < Duration::from_secs(secs)) {
+                trace.read(&mut core, &mut channels)?;
+            }
+            trace.finish()?;
+        }
+        Some(Host::Serial(path, port)) => {
+            println!("Attached ... serving on {path}");
+
+            let mut io = SerialIo(port);
+            loop {
+                // keep recording and capturing until SystemView says HELLO
+                while io
+                    .0
+                    .bytes_to_read()
+                    .with_context(|| format!("Error reading {path}"))?
+                    == 0
+                {
+                    trace.read(&mut core, &mut channels)?;
+                }
+
+                serve(
+                    UartTransport::default(),
+                    &mut io,
+                    &mut core,
+                    &mut channels,
+                    &mut trace,
+                )?;
+            }
+        }
+        Some(Host::Tcp(listener, mut first)) => {
+            println!("Attached ... listening on :7878");
+
+            listener
+                .set_nonblocking(true)
+                .context("Nonblocking support is required")?;
+
+            loop {
+                let stream = match first.take() {
+                    Some(stream) => stream,
+                    None => match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        // keep recording and capturing until SystemView connects
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                            trace.read(&mut core, &mut channels)?;
+                            continue;
+                        }
+                        Err(err) => return Err(err).context("Error accepting a connection"),
+                    },
+                };
+
+                println!("Connection established!");
+                stream
+                    .set_nonblocking(true)
+                    .context("Nonblocking support is required")?;
+
+                serve(
+                    TcpTransport::default(),
+                    stream,
+                    &mut core,
+                    &mut channels,
+                    &mut trace,
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn list_probes(lister: &Lister) {
+    let probes = lister.list_all();
+
+    if probes.is_empty() {
+        println!("No debug probes found");
+    }
+
+    for probe in probes {
+        println!("{probe}");
+    }
+}
+
+/// Open the selected probe - or the only one connected
+fn open_probe(lister: &Lister, selector: Option<&DebugProbeSelector>) -> anyhow::Result<Probe> {
+    if let Some(selector) = selector {
+        return lister
+            .open(selector.clone())
+            .with_context(|| format!("Error opening probe {selector}"));
+    }
+
+    let probes = lister.list_all();
+    match probes.as_slice() {
+        [] => bail!("No debug probes available. Make sure your probe is plugged in, supported and up-to-date."),
+        [probe] => probe.open().context("Error opening the debug probe"),
+        _ => {
+            let probes: Vec<String> = probes.iter().map(ToString::to_string).collect();
+            bail!(
+                "Multiple debug probes found - select one with --probe:\n{}",
+                probes.join("\n")
+            )
+        }
+    }
+}
+
+/// Where SystemView connects
+enum Host {
+    Serial(String, Box<dyn SerialPort>),
+    /// The listener and the connection accepted by [Host::wait]
+    Tcp(TcpListener, Option<TcpStream>),
+}
+
+impl Host {
+    /// Serve SystemView's UART recorder on `serial` - TCP if not given
+    fn open(serial: Option<&str>, baud: u32) -> anyhow::Result<Self> {
+        Ok(match serial {
+            Some(path) => Host::Serial(path.to_string(), open_serial(path, baud)?),
+            None => Host::Tcp(
+                TcpListener::bind("127.0.0.1:7878").context("Error listening on :7878")?,
+                None,
+            ),
+        })
+    }
+
+    /// Block until SystemView connects
+    fn wait(&mut self) -> anyhow::Result<()> {
+        match self {
+            Host::Serial(path, port) => {
+                while port
+                    .bytes_to_read()
+                    .with_context(|| format!("Error reading {path}"))?
+                    == 0
+                {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+            Host::Tcp(listener, first) => {
+                let (stream, _) = listener.accept().context("Error accepting a connection")?;
+                first.replace(stream);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn open_serial(path: &str, baud: u32) -> anyhow::Result<Box<dyn SerialPort>> {
+    serialport::new(path, baud)
+        .timeout(Duration::from_millis(1))
+        .open()
+        .with_context(|| format!("Error opening {path}"))
+}
+
+fn flash(session: &mut Session, path: &Path) -> anyhow::Result<()> {
+    println!("Flashing {} ...", path.display());
+
+    download_file(session, path, Format::Idf(Default::default()))
+        .with_context(|| format!("Error flashing {}", path.display()))
+}
+
+/// Attach to the RTT control block
+///
+/// Right after a reset the target didn't set it up yet - keep trying for a while then.
+fn attach_rtt(core: &mut Core, scan_region: &ScanRegion, after_reset: bool) -> anyhow::Result<Rtt> {
+    let start = Instant::now();
+
+    loop {
+        match Rtt::attach_region(core, scan_region) {
+            Ok(rtt) => break Ok(rtt),
+            Err(_) if after_reset && start.elapsed() < Duration::from_secs(10) => {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(err) => break Err(err).context("Error attaching to RTT"),
+        }
+    }
+}
+
+fn load_elf(path: &Path) -> anyhow::Result<Elf> {
+    let elf = std::fs::read(path).with_context(|| format!("Error reading {}", path.display()))?;
+    Elf::parse(&elf).with_context(|| format!("Error parsing {}", path.display()))
+}
+
+/// SHA-256 of a file
+fn sha256(path: &Path) -> anyhow::Result<String> {
+    let data = std::fs::read(path).with_context(|| format!("Error reading {}", path.display()))?;
+    Ok(format!("{:x}", Sha256::digest(data)))
+}
+
+/// The RTT channel with the trace and the ones forwarded to stdout
+struct Channels<'a> {
+    trace: &'a mut UpChannel,
+    logs: Vec<&'a mut UpChannel>,
+}
+
+impl Channels<'_> {
+    /// Read from the trace channel - prints whatever arrived on the other channels
+    fn read(&mut self, core: &mut Core, buf: &mut [u8]) -> anyhow::Result<usize> {
+        for channel in &mut self.logs {
+            let len = channel.read(core, buf).context("Error reading from RTT")?;
+            if len > 0 {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&buf[..len])?;
+                stdout.flush()?;
+            }
+        }
+
+        self.trace.read(core, buf).context("Error reading from RTT")
+    }
+}
+
+/// A SystemView data file being recorded to
+type Recorder = SystemViewTarget<SvDatTransport, RecordIo>;
+
+/// How long to wait for the system descriptor before falling back to the defaults
+const DESCRIPTOR_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// State kept across SystemView connections
+struct Trace {
+    decoder: StreamDecoder,
+    /// The system descriptor last sent by the target
+    system_info: Option<SystemInfo>,
+    /// Current time of the target in timer ticks - all events read so far add up to it
+    systime: u64,
+    /// When tracing started - to stop waiting for the system descriptor eventually
+    start: Instant,
+    elf: Option<Elf>,
+    /// File to record to - the recording starts once the system descriptor arrived
+    record: Option<PathBuf>,
+    recording: Option<Recorder>,
+    /// Events read before the recording started
+    unrecorded: Vec<TargetEvent>,
+    capture: Option<CaptureWriter<BufWriter<File>>>,
+}
+
+impl Trace {
+    fn new(
+        elf: Option<Elf>,
+        record: Option<PathBuf>,
+        capture: Option<CaptureWriter<BufWriter<File>>>,
+    ) -> Self {
+        Self {
+            decoder: StreamDecoder::default(),
+            system_info: None,
+            systime: 0,
+            start: Instant::now(),
+            elf,
+            record,
+            recording: None,
+            unrecorded: Vec::new(),
+            capture,
+        }
+    }
+
+    /// Read and decode what the target sent - recording and capturing it if requested
+    fn read(
+        &mut self,
+        core: &mut Core,
+        channels: &mut Channels,
+    ) -> anyhow::Result<Vec<TargetEvent>> {
+        let mut buf = [0u8; 1024];
+        let len = channels.read(core, &mut buf)?;
+        self.push(&buf[..len])
+    }
+
+    /// Decode `data` read from the target - recording and capturing it if requested
+    fn push(&mut self, data: &[u8]) -> anyhow::Result<Vec<TargetEvent>> {
+        self.decoder.push(data);
+
+        if let Some(capture) = &mut self.capture {
+            capture
+                .write(data)
+                .and_then(|()| capture.flush())
+                .context("Error writing the capture")?;
+        }
+
+        let events: Vec<TargetEvent> = self.decoder.by_ref().collect();
+        for event in &events {
+            self.systime += event.message().ts_delta() as u64;
+            match event.system_info() {
+                Some(info) if !info.is_valid() => log::warn!("Ignoring {:x?}", info),
+                Some(info) => self.system_info = Some(info),
+                None => (),
+            }
+        }
+
+        if self.recording.is_none() && self.record.is_some() {
+            self.unrecorded.extend(events.iter().cloned());
+            if let Some(info) = self.system_info() {
+                self.start_recording(info)?;
+            }
+        } else if let Some(recording) = &mut self.recording {
+            for event in &events {
+                forward(recording, event, self.elf.as_ref())
+                    .map_err(|err| anyhow::anyhow!("Error writing the recording: {err:?}"))?;
+            }
+            recording.flush().context("Error writing the recording")?;
+        }
+
+        Ok(events)
+    }
+
+    /// The system descriptor to start SystemView sessions with
+    ///
+    /// `None` while waiting for the target to send it - the defaults once that took too long.
+    fn system_info(&mut self) -> Option<SystemInfo> {
+        if self.system_info.is_none() && self.start.elapsed() >= DESCRIPTOR_TIMEOUT {
+            return Some(self.use_default_system_info());
+        }
+
+        self.system_info
+    }
+
+    fn use_default_system_info(&mut self) -> SystemInfo {
+        let info = SystemInfo::default();
+        eprintln!(
+            "No system descriptor from the target - using the defaults {:x?}. \
+             Timestamps are off unless the target has a {} Hz timer. \
+             The target only sends it on startup, trace it from reset with `run`.",
+            info, info.sys_freq
+        );
+        self.system_info = Some(info);
+        info
+    }
+
+    /// Start a SystemView session at the current time of the target
+    ///
+    /// `pending` are the events read but not forwarded yet - they count once forwarded.
+    fn connect<T, IO>(
+        &self,
+        transport: T,
+        io: IO,
+        info: SystemInfo,
+        pending: &[TargetEvent],
+    ) -> Result<SystemViewTarget<T, IO>, Error>
+    where
+        T: Transport<IO>,
+        IO: Read + Write,
+    {
+        let pending_ticks: u64 = pending
+            .iter()
+            .map(|event| event.message().ts_delta() as u64)
+            .sum();
+
+        SystemViewTarget::new(transport, io, info, self.systime - pending_ticks)
+    }
+
+    fn start_recording(&mut self, info: SystemInfo) -> anyhow::Result<()> {
+        let Some(path) = self.record.take() else {
+            return Ok(());
+        };
+        let unrecorded = std::mem::take(&mut self.unrecorded);
 
-    let mut rtt = match Rtt::attach_region(&mut core,  &ScanRegion::Ram) {
-        Ok(rtt) => rtt,
+        let file =
+            File::create(&path).with_context(|| format!("Error creating {}", path.display()))?;
+        let mut recording = self
+            .connect(
+                SvDatTransport::default(),
+                RecordIo(BufWriter::new(file)),
+                info,
+                &unrecorded,
+            )
+            .and_then(|mut recording| {
+                unrecorded
+                    .iter()
+                    .try_for_each(|event| forward(&mut recording, event, self.elf.as_ref()))?;
+                Ok(recording)
+            })
+            .map_err(|err| anyhow::anyhow!("Error writing {}: {err:?}", path.display()))?;
+        recording.flush().context("Error writing the recording")?;
+
+        self.recording = Some(recording);
+        Ok(())
+    }
+
+    /// Start the recording if it's still waiting for the system descriptor
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if self.record.is_none() {
+            return Ok(());
+        }
+
+        let info = match self.system_info {
+            Some(info) => info,
+            None => self.use_default_system_info(),
+        };
+        self.start_recording(info)
+    }
+}
+
+/// Forward target events to SystemView until the host disconnects
+fn serve<T, IO>(
+    transport: T,
+    io: IO,
+    core: &mut Core,
+    channels: &mut Channels,
+    trace: &mut Trace,
+) -> anyhow::Result<()>
+where
+    T: Transport<IO>,
+    IO: Read + Write,
+{
+    // the system descriptor is the first thing the target sends - wait for it before the handshake
+    let mut pending = Vec::new();
+    let info = loop {
+        pending.extend(trace.read(core, channels)?);
+        if let Some(info) = trace.system_info() {
+            break info;
+        }
+    };
+
+    let mut xray = match trace.connect(transport, io, info, &pending) {
+        Ok(xray) => xray,
         Err(err) => {
-            panic!("Error attaching to RTT: {err}");
+            println!("Handshake failed: {:?}", err);
+            return Ok(());
         }
     };
 
-    let up_channel = &mut rtt.up_channels()[0];
+    let mut events = pending;
+    loop {
+        let forwarded = events
+            .iter()
+            .try_for_each(|event| forward(&mut xray, event, trace.elf.as_ref()));
+        if forwarded.is_err() {
+            println!("Disconnected");
+            break;
+        }
+
+        match xray.process_incoming() {
+            Ok(()) => (),
+            Err(Error::UnknownCommand) => println!("Ignoring unknown command"),
+            Err(_) => {
+                println!("Disconnected");
+                break;
+            }
+        }
+
+        events = trace.read(core, channels)?;
+    }
+
+    Ok(())
+}
 
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+fn replay_file(path: &Path, speedup: f64, host: Host) -> anyhow::Result<()> {
+    let data = std::fs::read(path).with_context(|| format!("Error reading {}", path.display()))?;
+    let recording =
+        Recording::parse(&data).with_context(|| format!("Error parsing {}", path.display()))?;
+    let messages = recording.messages();
 
-    println!("Attached ... listening on :7878");
+    match host {
+        Host::Serial(path, port) => {
+            println!("Replaying ... serving on {path}");
 
-    if core.core_halted().unwrap() {
-        core.run().unwrap();
+            let mut io = SerialIo(port);
+            loop {
+                serve_replay(
+                    UartTransport::default(),
+                    &mut io,
+                    &recording,
+                    &messages,
+                    speedup,
+                );
+            }
+        }
+        Host::Tcp(listener, _) => {
+            println!("Replaying ... listening on :7878");
+
+            for stream in listener.incoming() {
+                let stream = stream.context("Error accepting a connection")?;
+
+                println!("Connection established!");
+                stream
+                    .set_nonblocking(true)
+                    .context("Nonblocking support is required")?;
+
+                serve_replay(
+                    TcpTransport::default(),
+                    stream,
+                    &recording,
+                    &messages,
+                    speedup,
+                );
+            }
+        }
     }
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    Ok(())
+}
 
-        println!("Connection established!");
-        stream
-            .set_nonblocking(true)
-            .expect("Nonblocking support is required");
+fn export(
+    path: &Path,
+    output: &Path,
+    format: ExportFormat,
+    elf: Option<&Elf>,
+) -> anyhow::Result<()> {
+    let data = std::fs::read(path).with_context(|| format!("Error reading {}", path.display()))?;
+    let recording =
+        Recording::parse(&data).with_context(|| format!("Error parsing {}", path.display()))?;
+    let messages = recording.messages();
 
-        let mut xray = esp_xray_server::SystemViewTarget::new(
-            esp_xray_server::TcpTransport::default(),
-            stream,
-        );
+    match format {
+        ExportFormat::Chrome => {
+            let trace = export::convert(ChromeTrace::new(recording.system_info()), &messages, elf);
 
-        let mut buf = [0u8; 1024];
-        loop {
-            if xray.process_incoming() {
-                println!("Disconnect requested");
+            let file = File::create(output)
+                .with_context(|| format!("Error creating {}", output.display()))?;
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer(&mut writer, &trace)
+                .with_context(|| format!("Error writing {}", output.display()))?;
+            writer
+                .flush()
+                .with_context(|| format!("Error writing {}", output.display()))?;
+        }
+        ExportFormat::Ctf => {
+            export::convert(CtfTrace::new(recording.system_info()), &messages, elf)
+                .write(output)
+                .with_context(|| format!("Error writing {}", output.display()))?;
+        }
+    }
+
+    println!("Exported {} events to {}", messages.len(), output.display());
+
+    Ok(())
+}
+
+/// Replay a recording to SystemView - then wait for the host to disconnect
+fn serve_replay<T, IO>(
+    transport: T,
+    io: IO,
+    recording: &Recording,
+    messages: &[Message],
+    speedup: f64,
+) where
+    T: Transport<IO>,
+    IO: Read + Write,
+{
+    let mut xray =
+        match SystemViewTarget::new(transport, io, recording.system_info(), recording.systime()) {
+            Ok(xray) => xray,
+            Err(err) => {
+                println!("Handshake failed: {:?}", err);
+                return;
+            }
+        };
+
+    if replay(&mut xray, messages, speedup).is_err() {
+        println!("Disconnected");
+        return;
+    }
+    println!("Replay finished");
+
+    loop {
+        match xray.process_incoming() {
+            Ok(()) => std::thread::sleep(Duration::from_millis(10)),
+            Err(Error::UnknownCommand) => println!("Ignoring unknown command"),
+            Err(_) => {
+                println!("Disconnected");
                 break;
             }
+        }
+    }
+}
 
-            let len = up_channel.read(&mut core, &mut buf).unwrap();
-
-            if len != 0 {
-                let mut pos = 0;
-                while pos < len {
-                    let target_event = TargetEvent::from(buf[pos]);
-                    match target_event {
-                        TargetEvent::TaskNew => {
-                            pos += 1;
-                            let (index, task) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            let (index, ts_delta) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            xray.send(Message::TaskNew(task, ts_delta));
-                        }
-                        TargetEvent::TaskExecBegin => {
-                            pos += 1;
-                            let (index, task) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            let (index, ts_delta) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            xray.send(Message::TaskExecBegin(task, ts_delta));
-                        }
-                        TargetEvent::TaskExecEnd => {
-                            pos += 1;
-                            let (index, ts_delta) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            xray.send(Message::TaskExecEnd(ts_delta));
-                        }
-                        TargetEvent::TaskReadyBegin => {
-                            pos += 1;
-                            let (index, task) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            let (index, ts_delta) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            xray.send(Message::TaskReadyBegin(task, ts_delta));
-                        }
-                        TargetEvent::TaskReadyEnd => {
-                            pos += 1;
-                            let (index, task) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            let (index, ts_delta) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos += index;
-                            xray.send(Message::TaskReadyEnd(task, ts_delta));
-                        }
-                        TargetEvent::SystemIdle => {
-                            pos += 1;
-                            let (index, ts_delta) = esp_xray_server::packet::decode_u32(&buf, pos);
-                            pos = index;
-                            xray.send(Message::SystemIdle(ts_delta));
-                        }
-                    }
-                }
+/// Send an event to SystemView - tasks get named after their static in the firmware image
+///
+/// Task info sent by the target keeps its priority and stack, only the name is replaced.
+fn forward<T, IO>(
+    xray: &mut SystemViewTarget<T, IO>,
+    event: &TargetEvent,
+    elf: Option<&Elf>,
+) -> Result<(), Error>
+where
+    T: Transport<IO>,
+    IO: Read + Write,
+{
+    let message = event.message();
+    let Some(elf) = elf else {
+        return xray.send(message);
+    };
+
+    match message {
+        Message::TaskNew(task, _) => {
+            xray.send(message)?;
+            match elf.task_name(task) {
+                Some(name) => xray.send(Message::TaskInfo(task, 0, &name, 0, 0, 0)),
+                None => Ok(()),
             }
         }
+        Message::TaskInfo(task, prio, _, stack_base, stack_size, ts_delta) => {
+            match elf.task_name(task) {
+                Some(name) => xray.send(Message::TaskInfo(
+                    task, prio, &name, stack_base, stack_size, ts_delta,
+                )),
+                None => xray.send(message),
+            }
+        }
+        _ => xray.send(message),
+    }
+}
+
+/// Makes a serial port non-blocking
+struct SerialIo(Box<dyn SerialPort>);
+
+impl Read for SerialIo {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                Err(std::io::ErrorKind::WouldBlock.into())
+            }
+            result => result,
+        }
+    }
+}
+
+impl Write for SerialIo {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.0.write(buf) {
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                Err(std::io::ErrorKind::WouldBlock.into())
+            }
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A recording - there is nothing to read from it
+struct RecordIo(BufWriter<File>);
+
+impl Read for RecordIo {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Write for RecordIo {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use esp_xray_protocol::Event;
+
+    use super::*;
+
+    const INFO: Event<&str> = Event::SystemInfo {
+        sys_freq: 1_000_000,
+        cpu_freq: 160_000_000,
+        ram_base: 0x4080_0000,
+        chip_id: 13,
+    };
+
+    fn frames(events: &[Event<&str>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for event in events {
+            let mut buf = [0u8; esp_xray_protocol::MAX_FRAME_LEN];
+            let len = event.encode_frame(&mut buf).unwrap();
+            data.extend_from_slice(&buf[..len]);
+        }
+        data
+    }
+
+    fn connect(
+        trace: &mut Trace,
+        pending: &[TargetEvent],
+    ) -> SystemViewTarget<SvDatTransport, Cursor<Vec<u8>>> {
+        let info = trace.system_info().unwrap();
+        trace
+            .connect(
+                SvDatTransport::default(),
+                Cursor::new(Vec::new()),
+                info,
+                pending,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let mut trace = Trace::new(None, None, None);
+        let pending = trace
+            .push(&frames(&[
+                INFO,
+                Event::TaskNew {
+                    task: 0x4080_1000,
+                    ts_delta: 5,
+                },
+            ]))
+            .unwrap();
+
+        let mut xray = connect(&mut trace, &pending);
+        assert_eq!(0, xray.systime());
+        for event in &pending {
+            forward(&mut xray, event, None).unwrap();
+        }
+        assert_eq!(5, xray.systime());
+
+        // the target keeps running while SystemView isn't connected
+        trace
+            .push(&frames(&[Event::SystemIdle { ts_delta: 100 }]))
+            .unwrap();
+
+        let xray = connect(&mut trace, &[]);
+        assert_eq!(105, xray.systime());
+        assert_eq!(1_000_000, xray.system_info().sys_freq);
+    }
+
+    #[test]
+    fn test_record_waits_for_descriptor() {
+        let path = std::env::temp_dir().join(format!("esp-xray-{}.SVDat", std::process::id()));
+        let mut trace = Trace::new(None, Some(path.clone()), None);
+
+        trace
+            .push(&frames(&[Event::SystemIdle { ts_delta: 5 }]))
+            .unwrap();
+        assert!(!path.exists());
+
+        trace.push(&frames(&[INFO])).unwrap();
+        trace
+            .push(&frames(&[Event::SystemIdle { ts_delta: 7 }]))
+            .unwrap();
+        drop(trace);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let recording = Recording::parse(&data).unwrap();
+
+        assert_eq!(1_000_000, recording.system_info().sys_freq);
+        assert_eq!(0, recording.systime());
+        assert_eq!(
+            &[Message::SystemIdle(5), Message::SystemIdle(7)],
+            &recording.messages()[..]
+        );
     }
 }
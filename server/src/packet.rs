@@ -1,6 +1,7 @@
 use super::*;
+use esp_xray_protocol::{decode_u32, encode_u32};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Cause {
     Idle,
@@ -8,7 +9,7 @@ pub enum Cause {
 }
 
 /// Events from the target
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Event<'a> {
     Overflow {
@@ -125,12 +126,12 @@ impl<'a> Event<'a> {
     }
 
     pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, Error> {
-        buffer[0] = self.discriminant();
+        *buffer.get_mut(0).ok_or(Error::BufferTooSmall)? = self.discriminant();
         let mut count = 1;
 
         // ids > 24 have a length - use a placeholder of one byte which means max 0x7f (excluding event_id, length and timestamp)
         if self.discriminant() >= 24 {
-            buffer[1] = 0;
+            *buffer.get_mut(1).ok_or(Error::BufferTooSmall)? = 0;
             count += 1;
         }
 
@@ -139,21 +140,21 @@ impl<'a> Event<'a> {
                 dropped_packets,
                 ts_delta,
             } => {
-                count = encode_u32(*dropped_packets, buffer, count);
+                count = encode_u32(*dropped_packets, buffer, count)?;
                 ts_delta
             }
             Event::IsrEnter { isr, ts_delta } => {
-                count = encode_u32(*isr as u32, buffer, count);
+                count = encode_u32(*isr as u32, buffer, count)?;
                 ts_delta
             }
             Event::IsrExit { ts_delta } => ts_delta,
             Event::TaskStartExec { task, ts_delta } => {
-                count = encode_u32(*task as u32, buffer, count);
+                count = encode_u32(*task as u32, buffer, count)?;
                 ts_delta
             }
             Event::TaskStopExec { ts_delta } => ts_delta,
             Event::TaskStartReady { task, ts_delta } => {
-                count = encode_u32(*task as u32, buffer, count);
+                count = encode_u32(*task as u32, buffer, count)?;
                 ts_delta
             }
             Event::TaskStopReady {
@@ -161,12 +162,12 @@ impl<'a> Event<'a> {
                 cause,
                 ts_delta,
             } => {
-                count = encode_u32(*task as u32, buffer, count);
-                count = encode_u32(*cause as u32, buffer, count);
+                count = encode_u32(*task as u32, buffer, count)?;
+                count = encode_u32(*cause as u32, buffer, count)?;
                 ts_delta
             }
             Event::TaskCreate { task, ts_delta } => {
-                count = encode_u32(*task as u32, buffer, count);
+                count = encode_u32(*task as u32, buffer, count)?;
                 ts_delta
             }
             Event::TaskInfo {
@@ -175,34 +176,34 @@ impl<'a> Event<'a> {
                 name,
                 ts_delta,
             } => {
-                count = encode_u32(*task as u32, buffer, count);
-                count = encode_u32(*prio as u32, buffer, count);
-                count = encode_str(*name, buffer, count);
+                count = encode_u32(*task as u32, buffer, count)?;
+                count = encode_u32(*prio as u32, buffer, count)?;
+                count = encode_str(*name, buffer, count)?;
                 ts_delta
             }
             Event::TraceStart { ts_delta } => ts_delta,
             Event::TraceStop { ts_delta } => ts_delta,
             Event::SystimeCycles { time, ts_delta } => {
-                count = encode_u32(*time as u32, buffer, count);
+                count = encode_u32(*time as u32, buffer, count)?;
                 ts_delta
             }
             Event::SystimeUs { time, ts_delta } => {
-                count = encode_u32(*time as u32, buffer, count);
-                count = encode_u32((*time >> 32) as u32, buffer, count);
+                count = encode_u32(*time as u32, buffer, count)?;
+                count = encode_u32((*time >> 32) as u32, buffer, count)?;
                 ts_delta
             }
             Event::UserStart { user_id, ts_delta } => {
-                count = encode_u32(*user_id as u32, buffer, count);
+                count = encode_u32(*user_id as u32, buffer, count)?;
                 ts_delta
             }
             Event::UserStop { user_id, ts_delta } => {
-                count = encode_u32(*user_id as u32, buffer, count);
+                count = encode_u32(*user_id as u32, buffer, count)?;
                 ts_delta
             }
             Event::Idle { ts_delta } => ts_delta,
             Event::IsrToScheduler { ts_delta } => ts_delta,
             Event::TimerEnter { timer_id, ts_delta } => {
-                count = encode_u32(*timer_id as u32, buffer, count);
+                count = encode_u32(*timer_id as u32, buffer, count)?;
                 ts_delta
             }
             Event::TimerExit { ts_delta } => ts_delta,
@@ -212,9 +213,9 @@ impl<'a> Event<'a> {
                 stack_size,
                 ts_delta,
             } => {
-                count = encode_u32(*task_id as u32, buffer, count);
-                count = encode_u32(*stack_base as u32, buffer, count);
-                count = encode_u32(*stack_size as u32, buffer, count);
+                count = encode_u32(*task_id as u32, buffer, count)?;
+                count = encode_u32(*stack_base as u32, buffer, count)?;
+                count = encode_u32(*stack_size as u32, buffer, count)?;
                 ts_delta
             }
             Event::Init {
@@ -224,10 +225,10 @@ impl<'a> Event<'a> {
                 id_shift,
                 ts_delta,
             } => {
-                count = encode_u32(*sys_freq as u32, buffer, count);
-                count = encode_u32(*cpu_freq as u32, buffer, count);
-                count = encode_u32(*ram_base as u32, buffer, count);
-                count = encode_u32(*id_shift as u32, buffer, count);
+                count = encode_u32(*sys_freq as u32, buffer, count)?;
+                count = encode_u32(*cpu_freq as u32, buffer, count)?;
+                count = encode_u32(*ram_base as u32, buffer, count)?;
+                count = encode_u32(*id_shift as u32, buffer, count)?;
                 ts_delta
             }
             Event::NameResource {
@@ -235,73 +236,221 @@ impl<'a> Event<'a> {
                 name,
                 ts_delta,
             } => {
-                count = encode_u32(*resource_id as u32, buffer, count);
-                count = encode_str(*name, buffer, count);
+                count = encode_u32(*resource_id as u32, buffer, count)?;
+                count = encode_str(*name, buffer, count)?;
                 ts_delta
             }
             Event::PrintFormatted { s, ts_delta } => {
-                count = encode_str(*s, buffer, count);
+                count = encode_str(*s, buffer, count)?;
                 ts_delta
             }
             Event::NumModules { modules, ts_delta } => {
-                count = encode_u32(*modules as u32, buffer, count);
+                count = encode_u32(*modules as u32, buffer, count)?;
                 ts_delta
             }
             Event::EndCall { event_id, ts_delta } => {
-                count = encode_u32(*event_id as u32, buffer, count);
+                count = encode_u32(*event_id as u32, buffer, count)?;
                 ts_delta
             }
             Event::TaskTerminate { task_id, ts_delta } => {
-                count = encode_u32(*task_id as u32, buffer, count);
+                count = encode_u32(*task_id as u32, buffer, count)?;
                 ts_delta
             }
         };
 
         if self.discriminant() >= 24 {
+            if count - 2 > 0x7f {
+                return Err(Error::BufferTooSmall);
+            }
             buffer[1] = (count - 2) as u8;
         }
 
-        count = encode_u32(*ts_delta, buffer, count);
+        count = encode_u32(*ts_delta, buffer, count)?;
         Ok(count)
     }
-}
 
-fn encode_u32(mut value: u32, buffer: &mut [u8], mut count: usize) -> usize {
-    while value > 0x7F {
-        buffer[count] = (value | 0x80) as u8;
-        count += 1;
-        value >>= 7;
+    /// Decode the packet at the start of `buffer`
+    ///
+    /// Returns the event and its length - `None` if the packet is incomplete or unknown.
+    pub fn decode(buffer: &'a [u8]) -> Option<(Self, usize)> {
+        let id = *buffer.first()?;
+        let mut r = Reader {
+            buffer,
+            index: 1,
+            end: None,
+        };
+
+        if id >= 24 {
+            let len = r.u32()?;
+            r.end = Some(r.index + len as usize);
+        }
+
+        let event = match id {
+            1 => Event::Overflow {
+                dropped_packets: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            2 => Event::IsrEnter {
+                isr: r.u32()? as u8,
+                ts_delta: r.ts_delta()?,
+            },
+            3 => Event::IsrExit {
+                ts_delta: r.ts_delta()?,
+            },
+            4 => Event::TaskStartExec {
+                task: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            5 => Event::TaskStopExec {
+                ts_delta: r.ts_delta()?,
+            },
+            6 => Event::TaskStartReady {
+                task: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            7 => Event::TaskStopReady {
+                task: r.u32()?,
+                cause: match r.u32()? {
+                    0 => Cause::Idle,
+                    _ => Cause::Sleep,
+                },
+                ts_delta: r.ts_delta()?,
+            },
+            8 => Event::TaskCreate {
+                task: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            9 => Event::TaskInfo {
+                task: r.u32()?,
+                prio: r.u32()?,
+                name: r.str()?,
+                ts_delta: r.ts_delta()?,
+            },
+            10 => Event::TraceStart {
+                ts_delta: r.ts_delta()?,
+            },
+            11 => Event::TraceStop {
+                ts_delta: r.ts_delta()?,
+            },
+            12 => Event::SystimeCycles {
+                time: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            13 => Event::SystimeUs {
+                time: r.u32()? as u64 | (r.u32()? as u64) << 32,
+                ts_delta: r.ts_delta()?,
+            },
+            15 => Event::UserStart {
+                user_id: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            16 => Event::UserStop {
+                user_id: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            17 => Event::Idle {
+                ts_delta: r.ts_delta()?,
+            },
+            18 => Event::IsrToScheduler {
+                ts_delta: r.ts_delta()?,
+            },
+            19 => Event::TimerEnter {
+                timer_id: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            20 => Event::TimerExit {
+                ts_delta: r.ts_delta()?,
+            },
+            21 => Event::StackInfo {
+                task_id: r.u32()?,
+                stack_base: r.u32()?,
+                stack_size: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            24 => Event::Init {
+                sys_freq: r.u32()?,
+                cpu_freq: r.u32()?,
+                ram_base: r.u32()?,
+                id_shift: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            25 => Event::NameResource {
+                resource_id: r.u32()?,
+                name: r.str()?,
+                ts_delta: r.ts_delta()?,
+            },
+            26 => Event::PrintFormatted {
+                s: r.str()?,
+                ts_delta: r.ts_delta()?,
+            },
+            27 => Event::NumModules {
+                modules: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            28 => Event::EndCall {
+                event_id: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            29 => Event::TaskTerminate {
+                task_id: r.u32()?,
+                ts_delta: r.ts_delta()?,
+            },
+            _ => return None,
+        };
+
+        Some((event, r.index))
     }
-    buffer[count] = value as u8;
-    count += 1;
+}
+
+/// The length is in bytes - the string is sent as UTF-8
+fn encode_str(s: &str, buffer: &mut [u8], mut count: usize) -> Result<usize, Error> {
+    count = encode_u32(s.len() as u32, buffer, count)?;
+    buffer
+        .get_mut(count..count + s.len())
+        .ok_or(Error::BufferTooSmall)?
+        .copy_from_slice(s.as_bytes());
+    count += s.len();
 
-    count
+    Ok(count)
 }
 
-fn encode_str(s: &str, buffer: &mut [u8], mut count: usize) -> usize {
-    count = encode_u32(s.len() as u32, buffer, count);
-    for c in s.chars().into_iter() {
-        buffer[count] = c as u8;
-        count += 1;
+struct Reader<'a> {
+    buffer: &'a [u8],
+    index: usize,
+    /// End of the payload of packets with a length
+    end: Option<usize>,
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> Option<u32> {
+        let (value, index) = decode_u32(self.buffer, self.index).ok()?;
+        self.index = index;
+        Some(value)
     }
 
-    count
-}
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.u32()? as usize;
+        let s = self.buffer.get(self.index..self.index + len)?;
+        self.index += len;
+
+        std::str::from_utf8(s).ok()
+    }
 
-pub fn decode_u32(buffer: &[u8], index: usize) -> (usize, u32) {
-    let mut index = index;
-    let mut value = 0u32;
-    let mut shift = 0u32;
+    /// Every packet ends with the timestamp - right after the payload
+    fn ts_delta(&mut self) -> Option<u32> {
+        if self.end.is_some_and(|end| end != self.index) {
+            return None;
+        }
 
-    while buffer[index] & 0x80 != 0 {
-        value |= ((buffer[index] & 0x7f) as u32) << shift;
-        shift += 7;
-        index += 1;
+        self.u32()
     }
-    value |= ((buffer[index] & 0x7f) as u32) << shift;
-    index += 1;
+}
 
-    (index, value)
+impl From<esp_xray_protocol::Error> for Error {
+    /// Encoding a varint only fails if it doesn't fit into the buffer
+    fn from(_: esp_xray_protocol::Error) -> Self {
+        Error::BufferTooSmall
+    }
 }
 
 /// Commands sent by host
@@ -333,25 +482,25 @@ mod test {
     #[test]
     fn test_encode_500() {
         let mut buffer = [0u8; 10];
-        let count = encode_u32(500, &mut buffer, 0);
+        let count = encode_u32(500, &mut buffer, 0).unwrap();
         assert_eq!(&[0xf4, 0x03], &buffer[..count]);
     }
 
     #[test]
     fn test_encode_0x7000() {
         let mut buffer = [0u8; 10];
-        let count = encode_u32(0x7000, &mut buffer, 0);
+        let count = encode_u32(0x7000, &mut buffer, 0).unwrap();
         assert_eq!(&[0x80, 0xE0, 0x01], &buffer[..count]);
     }
 
     #[test]
     fn test_decode0x50() {
-        assert_eq!((1, 0x50), decode_u32(&[0x50], 0));
+        assert_eq!(Ok((0x50, 1)), decode_u32(&[0x50], 0));
     }
 
     #[test]
     fn test_decode0x7000() {
-        assert_eq!((3, 0x7000), decode_u32(&[0x80, 0xE0, 0x01], 0));
+        assert_eq!(Ok((0x7000, 3)), decode_u32(&[0x80, 0xE0, 0x01], 0));
     }
 
     #[test]
@@ -378,6 +527,18 @@ mod test {
         assert_eq!(&[0x02, 0x0f, 0x50], &buffer[..count]);
     }
 
+    #[test]
+    fn test_encode_task_terminate() {
+        let mut buffer = [0u8; 10];
+        let count = Event::TaskTerminate {
+            task_id: 0x10,
+            ts_delta: 80,
+        }
+        .encode(&mut buffer)
+        .unwrap();
+        assert_eq!(&[0x1d, 0x01, 0x10, 0x50], &buffer[..count]);
+    }
+
     #[test]
     fn test_encode_init() {
         let mut buffer = [0u8; 10];
@@ -395,4 +556,113 @@ mod test {
             &buffer[..count]
         );
     }
+
+    #[test]
+    fn test_encode_utf8_name() {
+        let mut buffer = [0u8; 16];
+        let count = Event::TaskInfo {
+            task: 1,
+            prio: 2,
+            name: "grüß",
+            ts_delta: 3,
+        }
+        .encode(&mut buffer)
+        .unwrap();
+        assert_eq!(
+            &[0x09, 0x01, 0x02, 0x06, b'g', b'r', 0xc3, 0xbc, 0xc3, 0x9f, 0x03],
+            &buffer[..count]
+        );
+    }
+
+    #[test]
+    fn test_encode_buffer_too_small() {
+        let event = Event::TaskInfo {
+            task: 1,
+            prio: 2,
+            name: "a long task name",
+            ts_delta: 3,
+        };
+        assert!(matches!(
+            event.encode(&mut [0u8; 10]),
+            Err(Error::BufferTooSmall)
+        ));
+
+        let event = Event::NameResource {
+            resource_id: 1,
+            name: &"x".repeat(0x80),
+            ts_delta: 0,
+        };
+        assert!(matches!(
+            event.encode(&mut [0u8; 256]),
+            Err(Error::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn test_decode_roundtrip() {
+        let events = [
+            Event::Overflow {
+                dropped_packets: 3,
+                ts_delta: 0x50,
+            },
+            Event::IsrEnter {
+                isr: 15,
+                ts_delta: 500,
+            },
+            Event::TaskStopReady {
+                task: 0x40,
+                cause: Cause::Idle,
+                ts_delta: 0,
+            },
+            Event::TaskInfo {
+                task: 0x40,
+                prio: 1,
+                name: "main",
+                ts_delta: 0x7000,
+            },
+            Event::SystimeUs {
+                time: 0x1_0000_0005,
+                ts_delta: 1,
+            },
+            Event::Init {
+                sys_freq: 16_000_000,
+                cpu_freq: 160_000_000,
+                ram_base: 0x4000_0000,
+                id_shift: 2,
+                ts_delta: 1,
+            },
+            Event::NameResource {
+                resource_id: 7,
+                name: "phase",
+                ts_delta: 3,
+            },
+        ];
+
+        for event in events {
+            let mut buffer = [0u8; 64];
+            let count = event.encode(&mut buffer).unwrap();
+            assert_eq!(Some((event, count)), Event::decode(&buffer[..count + 1]));
+        }
+    }
+
+    #[test]
+    fn test_decode_incomplete() {
+        let mut buffer = [0u8; 64];
+        let count = Event::NameResource {
+            resource_id: 7,
+            name: "phase",
+            ts_delta: 0x7000,
+        }
+        .encode(&mut buffer)
+        .unwrap();
+
+        for len in 0..count {
+            assert_eq!(None, Event::decode(&buffer[..len]));
+        }
+    }
+
+    #[test]
+    fn test_decode_bad_length() {
+        assert_eq!(None, Event::decode(&[0x1d, 0x02, 0x10, 0x50]));
+    }
 }
@@ -0,0 +1,276 @@
+//! Serving recorded traces to SystemView
+
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::capture::{self, RawCapture};
+use crate::decoder::{StreamDecoder, TargetEvent};
+use crate::svdat::{self, SvDat};
+use crate::{Error, Message, SystemInfo, SystemViewTarget, Transport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    SvDat(svdat::Error),
+    Capture(capture::Error),
+    /// The timestamp frequency is 0 - timestamps can't be converted to time
+    InvalidSystemInfo,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::SvDat(err) => err.fmt(f),
+            ParseError::Capture(err) => err.fmt(f),
+            ParseError::InvalidSystemInfo => write!(f, "the timestamp frequency is 0"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<svdat::Error> for ParseError {
+    fn from(err: svdat::Error) -> Self {
+        ParseError::SvDat(err)
+    }
+}
+
+impl From<capture::Error> for ParseError {
+    fn from(err: capture::Error) -> Self {
+        ParseError::Capture(err)
+    }
+}
+
+/// A recorded trace
+pub enum Recording<'a> {
+    /// What the target sent
+    Raw(Vec<TargetEvent>),
+    SvDat(SvDat<'a>),
+}
+
+impl<'a> Recording<'a> {
+    /// SystemView data files and [RawCapture]s are recognized by their header - anything else is
+    /// taken as what the target sent
+    pub fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let recording = if data.first() == Some(&b';') {
+            Recording::SvDat(SvDat::parse(data)?)
+        } else {
+            let mut decoder = StreamDecoder::new();
+            if data.starts_with(capture::MAGIC) {
+                for chunk in RawCapture::parse(data)?.chunks {
+                    decoder.push(chunk.data);
+                }
+            } else {
+                decoder.push(data);
+            }
+            Recording::Raw(decoder.collect())
+        };
+
+        if !recording.system_info().is_valid() {
+            return Err(ParseError::InvalidSystemInfo);
+        }
+
+        Ok(recording)
+    }
+
+    pub fn system_info(&self) -> SystemInfo {
+        match self {
+            Recording::Raw(events) => events
+                .iter()
+                .find_map(TargetEvent::system_info)
+                .unwrap_or_default(),
+            Recording::SvDat(svdat) => svdat.system_info,
+        }
+    }
+
+    /// Time of the target in timer ticks when the recording started
+    pub fn systime(&self) -> u64 {
+        match self {
+            Recording::Raw(_) => 0,
+            Recording::SvDat(svdat) => svdat.systime,
+        }
+    }
+
+    pub fn messages(&self) -> Vec<Message<'_>> {
+        match self {
+            Recording::Raw(events) => events.iter().map(TargetEvent::message).collect(),
+            Recording::SvDat(svdat) => svdat.messages.clone(),
+        }
+    }
+}
+
+/// Send `messages` spaced out like they happened on the target - `speedup` times faster
+///
+/// Commands from the host are handled in between. Returns [Error::Disconnected] once the host
+/// closed the connection.
+pub fn replay<T, IO>(
+    xray: &mut SystemViewTarget<T, IO>,
+    messages: &[Message],
+    speedup: f64,
+) -> Result<(), Error>
+where
+    T: Transport<IO>,
+    IO: Read + Write,
+{
+    let start = Instant::now();
+    let ticks_per_sec = xray.system_info().sys_freq as f64 * speedup;
+    let mut ticks = 0u64;
+
+    for message in messages {
+        ticks += message.ts_delta() as u64;
+        let due = Duration::from_secs_f64(ticks as f64 / ticks_per_sec);
+
+        loop {
+            match xray.process_incoming() {
+                Ok(()) | Err(Error::UnknownCommand) => (),
+                Err(err) => return Err(err),
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= due {
+                break;
+            }
+            std::thread::sleep((due - elapsed).min(Duration::from_millis(10)));
+        }
+
+        xray.send(*message)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use esp_xray_protocol::Event;
+
+    use super::*;
+    use crate::capture::{CaptureWriter, Metadata};
+    use crate::SvDatTransport;
+
+    const INFO: SystemInfo = SystemInfo {
+        sys_freq: 1000,
+        cpu_freq: 80_000_000,
+        ram_base: 0x3fc8_0000,
+        chip_id: 5,
+    };
+
+    fn recorder() -> SystemViewTarget<SvDatTransport, Cursor<Vec<u8>>> {
+        SystemViewTarget::new(SvDatTransport::default(), Cursor::new(Vec::new()), INFO, 0).unwrap()
+    }
+
+    #[test]
+    fn test_raw() {
+        let mut data = Vec::new();
+        for event in [
+            Event::<&str>::SystemInfo {
+                sys_freq: INFO.sys_freq,
+                cpu_freq: INFO.cpu_freq,
+                ram_base: INFO.ram_base,
+                chip_id: INFO.chip_id,
+            },
+            Event::TaskNew {
+                task: 0x3fc8_1000,
+                ts_delta: 5,
+            },
+            Event::SystemIdle { ts_delta: 7 },
+        ] {
+            let mut buf = [0u8; esp_xray_protocol::MAX_FRAME_LEN];
+            let len = event.encode_frame(&mut buf).unwrap();
+            data.extend_from_slice(&buf[..len]);
+        }
+
+        let recording = Recording::parse(&data).unwrap();
+
+        assert_eq!(INFO, recording.system_info());
+        assert_eq!(
+            &[
+                Message::SystemInfo(INFO),
+                Message::TaskNew(0x3fc8_1000, 5),
+                Message::SystemIdle(7)
+            ],
+            &recording.messages()[..]
+        );
+    }
+
+    #[test]
+    fn test_zero_sys_freq() {
+        let mut buf = [0u8; esp_xray_protocol::MAX_FRAME_LEN];
+        let len = Event::<&str>::SystemInfo {
+            sys_freq: 0,
+            cpu_freq: INFO.cpu_freq,
+            ram_base: INFO.ram_base,
+            chip_id: INFO.chip_id,
+        }
+        .encode_frame(&mut buf)
+        .unwrap();
+
+        assert!(matches!(
+            Recording::parse(&buf[..len]),
+            Err(ParseError::InvalidSystemInfo)
+        ));
+    }
+
+    #[test]
+    fn test_raw_capture() {
+        let mut writer = CaptureWriter::new(Vec::new(), &Metadata::default()).unwrap();
+        let mut buf = [0u8; esp_xray_protocol::MAX_FRAME_LEN];
+        let len = Event::<&str>::SystemIdle { ts_delta: 7 }
+            .encode_frame(&mut buf)
+            .unwrap();
+        // a frame split across reads
+        writer.write(&buf[..1]).unwrap();
+        writer.write(&buf[1..len]).unwrap();
+        let data = writer.into_inner();
+
+        let recording = Recording::parse(&data).unwrap();
+
+        assert_eq!(&[Message::SystemIdle(7)], &recording.messages()[..]);
+    }
+
+    #[test]
+    fn test_svdat() {
+        let mut xray = recorder();
+        xray.send(Message::TaskExecBegin(0x3fc8_1000, 5)).unwrap();
+        let data = xray.io.into_inner();
+
+        let recording = Recording::parse(&data).unwrap();
+
+        assert_eq!(0, recording.system_info().chip_id);
+        assert_eq!(INFO.sys_freq, recording.system_info().sys_freq);
+        assert_eq!(
+            &[Message::TaskExecBegin(0x3fc8_1000, 5)],
+            &recording.messages()[..]
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let messages = [Message::SystemIdle(50), Message::TaskExecEnd(50)];
+
+        let mut expected = recorder();
+        for message in messages {
+            expected.send(message).unwrap();
+        }
+
+        let mut xray = recorder();
+        let start = Instant::now();
+        replay(&mut xray, &messages, 1.0).unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(expected.io.get_ref(), xray.io.get_ref());
+        assert_eq!(100, xray.systime());
+    }
+
+    #[test]
+    fn test_replay_speedup() {
+        let messages = [Message::SystemIdle(10_000)];
+
+        let mut xray = recorder();
+        let start = Instant::now();
+        replay(&mut xray, &messages, 1000.0).unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(10_000, xray.systime());
+    }
+}
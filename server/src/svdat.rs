@@ -0,0 +1,248 @@
+//! Reading SystemView data files (.SVDat) as written by [crate::SvDatTransport]
+
+use crate::packet::Event;
+use crate::{Message, SystemInfo};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The file doesn't start with a header and sync
+    Header,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Header => write!(f, "not a SystemView data file"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A recording turned back into what the target sent
+#[derive(Debug)]
+pub struct SvDat<'a> {
+    pub system_info: SystemInfo,
+    /// Time of the target in timer ticks when the recording started
+    pub systime: u64,
+    pub messages: Vec<Message<'a>>,
+}
+
+impl<'a> SvDat<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        if data.first() != Some(&b';') {
+            return Err(Error::Header);
+        }
+
+        let mut index = 0;
+        while data.get(index) == Some(&b';') {
+            let len = data[index..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .ok_or(Error::Header)?;
+            index += len + 1;
+        }
+
+        if data.get(index..index + 10) != Some(&[0u8; 10]) {
+            return Err(Error::Header);
+        }
+        index += 10;
+
+        let mut this = Self {
+            system_info: SystemInfo::default(),
+            systime: 0,
+            messages: Vec::new(),
+        };
+        let mut id_shift = 0;
+        let mut systime = None;
+        // task info and the stack info following it make up one message
+        let mut task_info: Option<(u32, u32, &str, u32)> = None;
+
+        while index < data.len() {
+            let Some((event, len)) = Event::decode(&data[index..]) else {
+                log::warn!("Ignoring undecodable data at offset {index}");
+                break;
+            };
+            index += len;
+
+            let task = |id: u32| this.system_info.ram_base.wrapping_add(id << id_shift);
+
+            if let Some((id, prio, name, ts_delta)) = task_info.take() {
+                match event {
+                    Event::StackInfo {
+                        task_id,
+                        stack_base,
+                        stack_size,
+                        ..
+                    } if task_id == id => {
+                        this.messages.push(Message::TaskInfo(
+                            task(id),
+                            prio,
+                            name,
+                            stack_base,
+                            stack_size,
+                            ts_delta,
+                        ));
+                        continue;
+                    }
+                    _ => {
+                        this.messages
+                            .push(Message::TaskInfo(task(id), prio, name, 0, 0, ts_delta))
+                    }
+                }
+            }
+
+            let message = match event {
+                Event::Overflow {
+                    dropped_packets,
+                    ts_delta,
+                } => Message::Overflow(dropped_packets, ts_delta),
+                Event::IsrEnter { isr, ts_delta } => Message::IsrEnter(isr, ts_delta),
+                Event::IsrExit { ts_delta } => Message::IsrExit(ts_delta),
+                Event::IsrToScheduler { ts_delta } => Message::IsrToScheduler(ts_delta),
+                Event::TaskCreate { task: id, ts_delta } => Message::TaskNew(task(id), ts_delta),
+                Event::TaskStartExec { task: id, ts_delta } => {
+                    Message::TaskExecBegin(task(id), ts_delta)
+                }
+                Event::TaskStopExec { ts_delta } => Message::TaskExecEnd(ts_delta),
+                Event::TaskStartReady { task: id, ts_delta } => {
+                    Message::TaskReadyBegin(task(id), ts_delta)
+                }
+                Event::TaskStopReady {
+                    task: id, ts_delta, ..
+                } => Message::TaskReadyEnd(task(id), ts_delta),
+                Event::TaskTerminate { task_id, ts_delta } => {
+                    Message::TaskTerminate(task(task_id), ts_delta)
+                }
+                Event::Idle { ts_delta } => Message::SystemIdle(ts_delta),
+                Event::UserStart { user_id, ts_delta } => Message::MarkerBegin(user_id, ts_delta),
+                Event::UserStop { user_id, ts_delta } => Message::MarkerEnd(user_id, ts_delta),
+                Event::NameResource {
+                    resource_id,
+                    name,
+                    ts_delta,
+                } => Message::MarkerName(resource_id, name, ts_delta),
+                Event::TaskInfo {
+                    task: id,
+                    prio,
+                    name,
+                    ts_delta,
+                } => {
+                    task_info = Some((id, prio, name, ts_delta));
+                    continue;
+                }
+                Event::Init {
+                    sys_freq,
+                    cpu_freq,
+                    ram_base,
+                    id_shift: shift,
+                    ..
+                } => {
+                    this.system_info = SystemInfo {
+                        sys_freq,
+                        cpu_freq,
+                        ram_base,
+                        chip_id: 0,
+                    };
+                    id_shift = shift;
+                    continue;
+                }
+                Event::SystimeCycles { time, .. } => {
+                    // sent when recording starts, again whenever SystemView asks for the time
+                    systime.get_or_insert(time as u64);
+                    continue;
+                }
+                event => {
+                    log::debug!("Skipping {:?}", event);
+                    continue;
+                }
+            };
+            this.messages.push(message);
+        }
+
+        if let Some((id, prio, name, ts_delta)) = task_info {
+            let task = this.system_info.ram_base.wrapping_add(id << id_shift);
+            this.messages
+                .push(Message::TaskInfo(task, prio, name, 0, 0, ts_delta));
+        }
+
+        this.systime = systime.unwrap_or_default();
+        Ok(this)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{SvDatTransport, SystemViewTarget};
+
+    fn record(info: SystemInfo, systime: u64, messages: &[Message]) -> Vec<u8> {
+        let mut xray = SystemViewTarget::new(
+            SvDatTransport::default(),
+            Cursor::new(Vec::new()),
+            info,
+            systime,
+        )
+        .unwrap();
+        for message in messages {
+            xray.send(*message).unwrap();
+        }
+
+        xray.io.into_inner()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let info = SystemInfo {
+            sys_freq: 1_000_000,
+            cpu_freq: 80_000_000,
+            ram_base: 0x3fc8_0000,
+            chip_id: 0,
+        };
+        let messages = [
+            Message::TaskNew(0x3fc8_1000, 5),
+            Message::TaskInfo(0x3fc8_1000, 0, "main", 0x3fc9_0000, 1024, 0),
+            Message::TaskInfo(0x3fc8_2000, 0, "blink", 0, 0, 0),
+            Message::TaskReadyBegin(0x3fc8_1000, 10),
+            Message::TaskExecBegin(0x3fc8_1000, 1),
+            Message::MarkerName(1, "phase", 0),
+            Message::MarkerBegin(1, 20),
+            Message::MarkerEnd(1, 300),
+            Message::TaskExecEnd(2),
+            Message::TaskReadyEnd(0x3fc8_1000, 0),
+            Message::IsrEnter(3, 7),
+            Message::IsrToScheduler(1),
+            Message::SystemIdle(4),
+            Message::Overflow(12, 1000),
+            Message::TaskTerminate(0x3fc8_2000, 5),
+        ];
+
+        let data = record(info, 1234, &messages);
+        let svdat = SvDat::parse(&data).unwrap();
+
+        assert_eq!(info, svdat.system_info);
+        assert_eq!(1234, svdat.systime);
+        assert_eq!(&messages[..], &svdat.messages[..]);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let messages = [Message::SystemIdle(4), Message::MarkerName(1, "phase", 0)];
+
+        let data = record(SystemInfo::default(), 0, &messages);
+        let svdat = SvDat::parse(&data[..data.len() - 2]).unwrap();
+
+        assert_eq!(&messages[..1], &svdat.messages[..]);
+    }
+
+    #[test]
+    fn test_not_svdat() {
+        assert_eq!(Error::Header, SvDat::parse(&[0xa5, 0x02]).unwrap_err());
+        assert_eq!(
+            Error::Header,
+            SvDat::parse(b";\n; Author\n;\n\0\0").unwrap_err()
+        );
+    }
+}